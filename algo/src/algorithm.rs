@@ -1,28 +1,30 @@
 use itertools::Itertools;
 
-use std::io::{self, Write};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
+use rayon::prelude::*;
 use rstar::RTree;
-use serde::{de, Deserialize};
+use serde::Deserialize;
 
 use crate::{
-    dp::douglas_peucker,
-    rest::{encode, Point, SubTrajectory},
-    spatial_filter::{PointWithIndexReference, SpatialQuery},
+    builder::{BuilderKind, SimilarityKind},
+    distance::{DistanceKind, TrajectoryDistance},
+    dp::Simplifier,
+    error::Error,
+    metric::PointMetric,
+    projection::ProjectionKind,
+    reference_set::EvictionPolicy,
+    rest::{encode, EncodedTrajectory, Point},
+    sampling::Sampling,
+    source::TrajectorySource,
+    spatial_filter::PointWithIndexReference,
+    usage::{CoverageReport, UsageFormat, UsageReport},
 };
 
-#[derive(Deserialize, Clone)]
-struct CsvTrajectory {
-    #[serde(deserialize_with = "deserialize_json_string")]
-    polyline: Vec<(f32, f32)>,
-}
-
-fn deserialize_json_string<'de, T: Deserialize<'de>, D: de::Deserializer<'de>>(
-    deserializer: D,
-) -> Result<T, D::Error> {
-    serde_json::from_str(Deserialize::deserialize(deserializer)?).map_err(de::Error::custom)
-}
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RestMode {
     pub rs: i32, //Reference set size in milliparts (thousandths)
     pub compression_ratio: i32,
@@ -30,21 +32,119 @@ pub struct RestMode {
     pub include_entire_trajectory: bool,
     pub k: usize,
     pub error_point: i32,
+    /// Most points the reference set holds while it is built, without limit when None
+    #[serde(default)]
+    pub budget: Option<usize>,
+    /// Which entries are evicted to stay within the budget
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+    #[serde(default)]
+    pub builder: BuilderKind,
+    /// Number of references the clustering builders pick, and most the segment builder does
+    #[serde(default)]
+    pub set_size: Option<usize>,
+    /// How the clustering builders compare trajectories
+    #[serde(default)]
+    pub similarity: SimilarityKind,
 }
-#[derive(Debug, Clone)]
-pub struct DpMode {}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DpMode {
+    #[serde(default)]
+    pub simplifier: Simplifier,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
     Rest(RestMode),
     DP(DpMode),
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub n: i32,
+    #[serde(alias = "error_trajectory")]
     pub max_dtw_dist: i32,
+    #[serde(default)]
     pub dtw_band: usize,
+    #[serde(default)]
+    pub distance: DistanceKind,
+    #[serde(default)]
+    pub metric: PointMetric,
+    #[serde(default)]
+    pub projection: ProjectionKind,
+    /// How the reference sample and the trajectories to compress are drawn from the input
+    #[serde(default)]
+    pub sampling: Sampling,
+    /// Seed of the random sampling modes, logged in the mode name
+    #[serde(default)]
+    pub seed: u64,
     pub mode: Mode,
 }
+impl Config {
+    /// The metric points are compared under once projected
+    pub fn point_metric(&self) -> PointMetric {
+        self.projection.metric(self.metric)
+    }
+
+    pub fn measure(&self) -> Box<dyn TrajectoryDistance> {
+        self.distance.measure(self.dtw_band, self.point_metric())
+    }
+
+    /// Describes every parameter that is out of range, empty if the config can be run
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.n <= 0 {
+            problems.push(format!("n must be positive, got {}", self.n));
+        }
+        if self.max_dtw_dist <= 0 {
+            problems.push(format!(
+                "max_dtw_dist must be positive, got {}",
+                self.max_dtw_dist
+            ));
+        }
+        if self.projection != ProjectionKind::None && self.metric != PointMetric::Haversine {
+            problems.push(format!(
+                "the {} metric applies to unprojected points, not with the {} projection",
+                self.metric.name(),
+                self.projection.name()
+            ));
+        }
+        if let PointMetric::Planar { latitude } = self.metric {
+            if !(-90.0 < latitude && latitude < 90.0) {
+                problems.push(format!(
+                    "the planar metric latitude must be within (-90, 90), got {latitude}"
+                ));
+            }
+        }
+        if let Mode::Rest(rest_conf) = self.mode {
+            if rest_conf.rs <= 0 {
+                problems.push(format!("rs must be positive, got {}", rest_conf.rs));
+            }
+            if rest_conf.compression_ratio <= 0 {
+                problems.push(format!(
+                    "compression_ratio must be positive, got {}",
+                    rest_conf.compression_ratio
+                ));
+            }
+            if rest_conf.spatial_filter && rest_conf.error_point <= 0 {
+                problems.push(format!(
+                    "error_point must be positive with the spatial filter, got {}",
+                    rest_conf.error_point
+                ));
+            }
+            if rest_conf.budget == Some(0) {
+                problems.push(String::from("budget must be positive"));
+            }
+            if rest_conf.builder.is_clustering() && rest_conf.set_size.unwrap_or(0) == 0 {
+                problems.push(String::from(
+                    "the clustering builders need a positive set_size",
+                ));
+            }
+        }
+        problems
+    }
+}
 #[derive(Debug)]
 pub struct PerformanceMetrics {
     pub avg_cr: f64,
@@ -53,182 +153,226 @@ pub struct PerformanceMetrics {
     pub runtime: std::time::Duration,
 }
 
+/// Bytes of a point in the compression ratio: i32 is 4 bytes, and x2 for lat and lng
+pub const POINT_SIZE: u64 = 4 * 2;
+/// Bytes of a reference in the compression ratio
+pub const REFERENCE_SIZE: u64 = 16;
+
+/// Trajectories compressed in parallel between two checks of `TIME_LIMIT`
+pub const BATCH_SIZE: usize = 256;
+/// Seconds after which a run stops compressing and reports what it has
+pub const TIME_LIMIT: f64 = 60.0 * 60.0 * 20.0;
+
 pub fn cr_from_shape(shape: (u64, u64, u64)) -> f64 {
-    // i32 is 4 bytes, and x2 for lat and lng
-    let point_size = 4.0 * 2.0;
-    // 8 byte reference
-    let reference_size = 16.0;
+    let point_size = POINT_SIZE as f64;
+    let reference_size = REFERENCE_SIZE as f64;
     (shape.0 as f64 * point_size)
         / ((shape.2 as f64 * point_size) + (shape.1 as f64 * reference_size))
 }
+pub fn reference_sample_size(conf: &Config, rest_conf: &RestMode) -> usize {
+    ((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as usize
+}
+pub fn read_trajectories(
+    source: &dyn TrajectorySource,
+    skip: usize,
+    take: usize,
+) -> Result<Vec<Vec<Point>>, Error> {
+    source
+        .trajectories()?
+        .skip(skip)
+        .take(take)
+        .map(|res| res.map(|traj| traj.points))
+        .collect()
+}
+fn open_log(out_dir: &Path, name: &str) -> Result<File, Error> {
+    Ok(File::options()
+        .create(true)
+        .append(true)
+        .open(out_dir.join(name))?)
+}
+/// Name of a run in the logs. Every option appends a suffix for its value, and the value
+/// runs had before the option existed appends none, so the names of earlier runs stay
+/// the same and their logs comparable.
+pub fn mode_name(conf: &Config) -> String {
+    match conf.mode {
+        Mode::Rest(rest_conf) => {
+            let mut mode_name = String::from("REST");
+            if !rest_conf.include_entire_trajectory {
+                mode_name.push_str("_EXCL");
+            }
+            if rest_conf.spatial_filter {
+                mode_name.push_str("-SF");
+                mode_name.push_str(&rest_conf.error_point.to_string());
+            }
+            if conf.dtw_band != 0 {
+                mode_name.push_str("-BND");
+                mode_name.push_str(&conf.dtw_band.to_string());
+            }
+            mode_name.push_str(conf.distance.suffix());
+            mode_name.push_str(conf.metric.suffix());
+            mode_name.push_str(conf.projection.suffix());
+            mode_name.push_str(&reference_set_suffix(&rest_conf));
+            mode_name.push_str(&conf.sampling.suffix(conf.seed));
+            if rest_conf.k != 0 {
+                mode_name.push_str("-KNN");
+                mode_name.push_str(&rest_conf.k.to_string());
+            }
+            mode_name
+        }
+        Mode::DP(dp_mode) => {
+            let mut mode_name = String::from(dp_mode.simplifier.mode_name());
+            if dp_mode.simplifier.uses_distance() {
+                if conf.dtw_band != 0 {
+                    mode_name.push_str("-BND");
+                    mode_name.push_str(&conf.dtw_band.to_string());
+                }
+                mode_name.push_str(conf.distance.suffix());
+            }
+            mode_name.push_str(conf.metric.suffix());
+            mode_name.push_str(conf.projection.suffix());
+            mode_name.push_str(&conf.sampling.suffix(conf.seed));
+            mode_name
+        }
+    }
+}
+/// Mode name used in set_size.txt, which predates the _EXCL and -KNN suffixes
+fn set_size_mode_name(conf: &Config, rest_conf: &RestMode) -> String {
+    let mut mode_name = String::from("REST");
+    if rest_conf.spatial_filter {
+        mode_name.push_str("-SF");
+        mode_name.push_str(&rest_conf.error_point.to_string());
+    }
+    if conf.dtw_band != 0 {
+        mode_name.push_str("-BND");
+        mode_name.push_str(&conf.dtw_band.to_string());
+    }
+    mode_name.push_str(conf.distance.suffix());
+    mode_name.push_str(conf.metric.suffix());
+    mode_name.push_str(conf.projection.suffix());
+    mode_name.push_str(&reference_set_suffix(rest_conf));
+    mode_name.push_str(&conf.sampling.suffix(conf.seed));
+    mode_name
+}
+/// Suffix of the mode name for how the reference set is built and bounded, empty for
+/// an unbounded greedy set
+fn reference_set_suffix(rest_conf: &RestMode) -> String {
+    let mut suffix = String::new();
+    if rest_conf.builder != BuilderKind::Greedy {
+        suffix.push_str(rest_conf.builder.suffix());
+        if let Some(set_size) = rest_conf.set_size {
+            suffix.push_str(&set_size.to_string());
+        }
+        suffix.push_str(rest_conf.similarity.suffix());
+    }
+    if let Some(budget) = rest_conf.budget {
+        suffix.push_str(&format!("-BGT{budget}{}", rest_conf.eviction.suffix()));
+    }
+    suffix
+}
+/// Builds a reference set from the sample with the builder of `rest_conf`, see
+/// `builder::ReferenceSetBuilder`. `on_progress` is called with the number of sample
+/// trajectories processed and the current set size.
+/// Returns the set, its R-tree if the spatial filter is on, and the number of points stored.
+pub fn build_reference_set(
+    conf: &Config,
+    rest_conf: &RestMode,
+    sample_to_build_reference_set: Vec<Vec<Point>>,
+    mut on_progress: impl FnMut(usize, usize),
+) -> (Vec<Vec<Point>>, Option<RTree<PointWithIndexReference>>, u64) {
+    let reference_set = rest_conf
+        .builder
+        .builder(rest_conf.set_size.unwrap_or(0), rest_conf.similarity)
+        .build(
+            conf,
+            rest_conf,
+            sample_to_build_reference_set,
+            &mut on_progress,
+        );
+    let raw_points = reference_set.points() as u64;
+    let (reference_set, r_tree) = reference_set.into_parts();
+    (reference_set, r_tree, raw_points)
+}
+/// Encodes `trajectories` in parallel against a frozen reference set, returning each
+/// encoding with its shape in input order
+pub fn encode_all<'a>(
+    conf: &Config,
+    rest_conf: &RestMode,
+    reference_set: &'a [&'a [Point]],
+    r_tree: Option<&RTree<PointWithIndexReference>>,
+    trajectories: &[Vec<Point>],
+) -> Vec<(EncodedTrajectory<'a>, (u64, u64, u64))> {
+    let distance = conf.measure();
+    trajectories
+        .par_iter()
+        .map(|t| {
+            encode(
+                reference_set,
+                t.as_slice(),
+                conf.max_dtw_dist as f64,
+                distance.as_ref(),
+                rest_conf.k,
+                r_tree,
+                rest_conf.error_point as f64,
+            )
+        })
+        .collect()
+}
+/// Simplifies `trajectories` in parallel with the simplifier of the DP mode, in input order
+pub fn simplify_all(conf: &Config, trajectories: &[Vec<Point>]) -> Vec<Vec<Point>> {
+    let simplifier = match conf.mode {
+        Mode::DP(dp_mode) => dp_mode.simplifier,
+        Mode::Rest(_) => Simplifier::default(),
+    };
+    let distance = conf.measure();
+    trajectories
+        .par_iter()
+        .map(|t| simplifier.simplify(t, conf.max_dtw_dist as f64 / 1000.0, distance.as_ref()))
+        .collect()
+}
 pub fn rest_main(
     conf: Config,
+    source: &dyn TrajectorySource,
+    out_dir: &Path,
     only_set: bool,
     log_n: i32,
-) -> Result<PerformanceMetrics, csv::Error> {
-    let mut set_size_file = std::fs::File::options()
-        .create(true)
-        .append(true)
-        .open("out/set_size.txt")
-        .expect("Failed to open or create the file");
-    let mut intermediate_file = std::fs::File::options()
-        .create(true)
-        .append(true)
-        .open("out/intermediate.txt")
-        .expect("Failed to open or create the file");
+    usage_report: Option<UsageFormat>,
+    coverage_report: bool,
+) -> Result<PerformanceMetrics, Error> {
+    let mut set_size_file = open_log(out_dir, "set_size.txt")?;
+    let mut intermediate_file = open_log(out_dir, "intermediate.txt")?;
     let begin = std::time::Instant::now();
+    let log_every = (conf.n / log_n).max(1);
     let mut compressed_points = 0;
     let mut references = 0;
-    let mut raw_points = 0;
     match conf.mode {
         Mode::Rest(rest_conf) => {
-            let sample_to_build_reference_set: Vec<Vec<Point>> =
-                csv::Reader::from_path("porto.csv")?
-                    .deserialize()
-                    .take(((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as usize)
-                    .map(|res| {
-                        res.map(|traj: CsvTrajectory| {
-                            traj.polyline.iter().map(|&pnt| pnt.into()).collect_vec()
-                        })
-                    })
-                    .collect::<Result<Vec<_>, _>>()?;
-
-            let mut reference_set: Vec<Vec<Point>> = Vec::new();
-            let mut r_tree: Option<RTree<PointWithIndexReference>> = if rest_conf.spatial_filter {
-                Some(RTree::<PointWithIndexReference>::new())
-            } else {
-                None
-            };
-
-            sample_to_build_reference_set
-                .into_iter()
-                .enumerate()
-                .for_each(|(i, t)| {
-                    let reference_vec = reference_set.iter().map(|t| t.as_slice()).collect_vec();
-                    let (encoded, shape) = encode(
-                        reference_vec.as_slice(),
-                        &t.as_slice(),
-                        conf.max_dtw_dist as f64,
-                        conf.dtw_band,
-                        rest_conf.k,
-                        r_tree.as_ref(),
-                        rest_conf.error_point as f64,
-                    );
-
-                    if cr_from_shape(shape) < rest_conf.compression_ratio as f64 {
-                        if rest_conf.include_entire_trajectory {
-                            if let Some(mut_tree) = r_tree.as_mut() {
-                                for (i, point) in t.iter().enumerate() {
-                                    mut_tree.insert(PointWithIndexReference {
-                                        point: point.clone(),
-                                        index: (reference_set.len(), i),
-                                    });
-                                }
-                            }
-                            reference_set.push(t);
-                            raw_points += shape.0;
-                        } else {
-                            let mut raw_trajectories_added = Vec::new();
-                            let mut first_point_index = 0;
-                            for st in encoded.0 {
-                                match &st {
-                                    SubTrajectory::Trajectory(raw_trajectory) => {
-                                        for p in raw_trajectory
-                                            [first_point_index..raw_trajectory.len()]
-                                            .iter()
-                                        {
-                                            raw_trajectories_added.push(Some(p.clone()));
-                                        }
-                                    }
-                                    // Successfully compressed, therefore not added to reference set
-                                    SubTrajectory::Reference(_) => {
-                                        raw_trajectories_added.push(None);
-                                    }
-                                }
-                                first_point_index = 1;
-                            }
-                            let mut current_batch = Vec::new();
-
-                            for item in raw_trajectories_added.iter() {
-                                match item {
-                                    Some(p) => current_batch.push(p.clone()),
-                                    None => {
-                                        if !current_batch.is_empty() {
-                                            raw_points += current_batch.len() as u64;
-                                            if let Some(mut_tree) = r_tree.as_mut() {
-                                                for (i, point) in current_batch.iter().enumerate() {
-                                                    mut_tree.insert(PointWithIndexReference {
-                                                        point: point.clone(),
-                                                        index: (reference_set.len(), i),
-                                                    });
-                                                }
-                                            }
-                                            reference_set.push(current_batch.clone()); // Push the current batch as one element
-                                            current_batch.clear(); // Reset the current batch
-                                        }
-                                    }
-                                }
-                            }
-                            if !current_batch.is_empty() {
-                                raw_points += current_batch.len() as u64;
-                                if let Some(mut_tree) = r_tree.as_mut() {
-                                    for (i, point) in current_batch.iter().enumerate() {
-                                        mut_tree.insert(PointWithIndexReference {
-                                            point: point.clone(),
-                                            index: (reference_set.len(), i),
-                                        });
-                                    }
-                                }
-
-                                reference_set.push(current_batch.clone()); // Push the current batch as one element
-                                current_batch.clear(); // Reset the current batch
-                            }
-                        }
-                    }
-
-                    if (i + 1) as i32
-                        % ((((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as i32) / 5)
-                        == 0
-                    {
-                        let _file_write_res = write!(
+            let sample_size = reference_sample_size(&conf, &rest_conf);
+            let eval_size = if only_set { 0 } else { conf.n as usize };
+            let (sample_to_build_reference_set, n_trajectories) =
+                conf.sampling
+                    .draw(source, conf.seed, sample_size, eval_size)?;
+            let projection = conf.projection.fit(&sample_to_build_reference_set);
+            let sample_to_build_reference_set =
+                projection.project_all(sample_to_build_reference_set);
+            let (reference_set, r_tree, mut raw_points) = build_reference_set(
+                &conf,
+                &rest_conf,
+                sample_to_build_reference_set,
+                |i, len| {
+                    if i % (sample_size / 5).max(1) == 0 {
+                        let _file_write_res = writeln!(
                             set_size_file,
-                            "{},{},{},{},{},{}\n",
-                            match conf.mode.clone() {
-                                Mode::Rest(rest_conf) => {
-                                    let mut mode_name = String::from("REST"); // Change to mutable String
-                                    if rest_conf.spatial_filter {
-                                        mode_name.push_str("-SF"); // Use push_str to append
-                                        mode_name.push_str(&rest_conf.error_point.to_string());
-                                        // Convert error_point to String and append
-                                    }
-                                    if conf.dtw_band != 0 {
-                                        mode_name.push_str("-BND"); // Append "-BND"
-                                        mode_name.push_str(&conf.dtw_band.to_string());
-                                        // Convert dtw_band to String and append
-                                    }
-                                    mode_name
-                                }
-                                Mode::DP(_) => {
-                                    let mut mode_name = String::from("DP");
-                                    if conf.dtw_band != 0 {
-                                        mode_name.push_str("-BND"); // Append "-BND"
-                                        mode_name.push_str(&conf.dtw_band.to_string());
-                                        // Convert dtw_band to String and append
-                                    }
-                                    mode_name
-                                }
-                            },
+                            "{},{},{},{:.2},{},{:.0}",
+                            set_size_mode_name(&conf, &rest_conf),
                             conf.max_dtw_dist,
-                            i + 1,
-                            format!(
-                                "{:.2}",
-                                ((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as usize
-                            ),
-                            reference_set.len(),
-                            format!("{:.0}", begin.elapsed().as_secs_f64()),
+                            i,
+                            sample_size,
+                            len,
+                            begin.elapsed().as_secs_f64(),
                         );
                     }
-                });
+                },
+            );
             if only_set {
                 return Ok(PerformanceMetrics {
                     avg_cr: 0.0,
@@ -238,100 +382,85 @@ pub fn rest_main(
                 });
             }
 
-            let n_trajectories: Vec<Vec<Point>> = csv::Reader::from_path("porto.csv")?
-                .deserialize()
-                .skip(((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as usize)
-                .take(conf.n as usize)
-                .map(|res| {
-                    res.map(|traj: CsvTrajectory| {
-                        traj.polyline
-                            .iter()
-                            .map(|&pnt| pnt.into())
-                            .collect::<Vec<_>>()
-                    })
-                })
-                .collect::<Result<Vec<_>, _>>()?;
+            let n_trajectories = projection.project_all(n_trajectories);
             let mut encoded_cr = Vec::new();
             let final_reference_vectors = reference_set.iter().map(|t| t.as_slice()).collect_vec();
-            for (i, t) in n_trajectories.iter().enumerate() {
-                let (encoded_trajectory, shape) = encode(
-                    final_reference_vectors.as_slice(),
-                    &t.as_slice(),
-                    conf.max_dtw_dist as f64,
-                    conf.dtw_band,
-                    rest_conf.k,
+            // Encode one batch at a time in parallel, then log it in order
+            for (batch_index, batch) in n_trajectories.chunks(BATCH_SIZE).enumerate() {
+                let encoded_batch = encode_all(
+                    &conf,
+                    &rest_conf,
+                    &final_reference_vectors,
                     r_tree.as_ref(),
-                    rest_conf.error_point as f64,
+                    batch,
                 );
-                encoded_cr.push((encoded_trajectory, shape));
-                compressed_points += shape.0;
-                references += shape.1;
-                raw_points += shape.2;
+                for (j, (encoded_trajectory, shape)) in encoded_batch.into_iter().enumerate() {
+                    let i = batch_index * BATCH_SIZE + j;
+                    encoded_cr.push((encoded_trajectory, shape));
+                    compressed_points += shape.0;
+                    references += shape.1;
+                    raw_points += shape.2;
 
-                if (i + 1) as i32 % (conf.n / log_n) == 0 {
-                    let avg_cr = encoded_cr
-                        .iter()
-                        .map(|&(_, shape)| cr_from_shape(shape))
-                        .sum::<f64>()
-                        / encoded_cr.len() as f64;
-                    let cr_set_inclusive =
-                        cr_from_shape((compressed_points, references, raw_points));
-                    let _file_write_res = write!(
-                        intermediate_file,
-                        "{},{},{},{},{},{},{},{}\n",
-                        match conf.mode.clone() {
-                            Mode::Rest(rest_conf) => {
-                                let mut mode_name = String::from("REST"); // Change to mutable String
-                                if !rest_conf.include_entire_trajectory {
-                                    mode_name.push_str("_EXCL");
-                                }
-                                if rest_conf.spatial_filter {
-                                    mode_name.push_str("-SF"); // Use push_str to append
-                                    mode_name.push_str(&rest_conf.error_point.to_string());
-                                    // Convert error_point to String and append
-                                }
-                                if conf.dtw_band != 0 {
-                                    mode_name.push_str("-BND"); // Append "-BND"
-                                    mode_name.push_str(&conf.dtw_band.to_string());
-                                    // Convert dtw_band to String and append
-                                }
-                                if rest_conf.k != 0 {
-                                    mode_name.push_str("-KNN");
-                                    mode_name.push_str(&rest_conf.k.to_string());
-                                }
-                                mode_name
-                            }
-                            Mode::DP(_) => {
-                                let mut mode_name = String::from("DP");
-                                if conf.dtw_band != 0 {
-                                    mode_name.push_str("-BND"); // Append "-BND"
-                                    mode_name.push_str(&conf.dtw_band.to_string());
-                                    // Convert dtw_band to String and append
-                                }
-                                mode_name
-                            }
-                        },
-                        i + 1,
-                        conf.max_dtw_dist,
-                        format!(
-                            "{:.2}",
-                            ((rest_conf.rs as f32 / 1000.0) * conf.n as f32) as usize
-                        ),
-                        rest_conf.k,
-                        format!("{:.0}", begin.elapsed().as_secs_f64()),
-                        format!("{:.2}", avg_cr),
-                        format!("{:.2}", cr_set_inclusive),
-                    );
-                    if begin.elapsed().as_secs_f64() > 60.0 * 60.0 * 20.0 {
-                        break;
+                    if (i + 1) as i32 % log_every == 0 {
+                        let avg_cr = encoded_cr
+                            .iter()
+                            .map(|&(_, shape)| cr_from_shape(shape))
+                            .sum::<f64>()
+                            / encoded_cr.len() as f64;
+                        let cr_set_inclusive =
+                            cr_from_shape((compressed_points, references, raw_points));
+                        let _file_write_res = writeln!(
+                            intermediate_file,
+                            "{},{},{},{:.2},{},{:.0},{:.2},{:.2}",
+                            mode_name(&conf),
+                            i + 1,
+                            conf.max_dtw_dist,
+                            sample_size,
+                            rest_conf.k,
+                            begin.elapsed().as_secs_f64(),
+                            avg_cr,
+                            cr_set_inclusive,
+                        );
                     }
                 }
+                if begin.elapsed().as_secs_f64() > TIME_LIMIT {
+                    break;
+                }
             }
             let avg_cr = encoded_cr
                 .iter()
                 .map(|&(_, shape)| cr_from_shape(shape))
                 .sum::<f64>()
                 / encoded_cr.len() as f64;
+            if coverage_report {
+                let mut coverage =
+                    CoverageReport::new(&reference_set, conf.point_metric(), conf.max_dtw_dist);
+                for (encoded_trajectory, shape) in &encoded_cr {
+                    coverage.record(encoded_trajectory, shape.0 as usize);
+                }
+                coverage.write_csv(
+                    open_log(out_dir, "coverage.txt")?,
+                    &mode_name(&conf),
+                    conf.max_dtw_dist,
+                    sample_size,
+                )?;
+            }
+            if let Some(format) = usage_report {
+                let mut report = UsageReport::new(&final_reference_vectors);
+                for (encoded_trajectory, _) in &encoded_cr {
+                    report.record(&encoded_trajectory.reference_uses(&final_reference_vectors));
+                }
+                let file = open_log(out_dir, format.file_name())?;
+                let name = mode_name(&conf);
+                match format {
+                    UsageFormat::Csv => {
+                        report.write_csv(file, &name, conf.max_dtw_dist, sample_size)?
+                    }
+                    UsageFormat::Json => {
+                        report.write_json(file, &name, conf.max_dtw_dist, sample_size)?
+                    }
+                }
+            }
 
             Ok(PerformanceMetrics {
                 avg_cr,
@@ -341,77 +470,41 @@ pub fn rest_main(
             })
         }
         Mode::DP(_) => {
-            let n_trajectories: Vec<Vec<Point>> = csv::Reader::from_path("porto.csv")?
-                .deserialize()
-                .take(conf.n as usize)
-                .map(|res| {
-                    res.map(|traj: CsvTrajectory| {
-                        traj.polyline
-                            .iter()
-                            .map(|&pnt| pnt.into())
-                            .collect::<Vec<_>>()
-                    })
-                })
-                .collect::<Result<Vec<_>, _>>()?;
+            let (_, n_trajectories) = conf.sampling.draw(source, conf.seed, 0, conf.n as usize)?;
+            let n_trajectories = conf
+                .projection
+                .fit(&n_trajectories)
+                .project_all(n_trajectories);
 
             let mut encoded_cr = Vec::new();
-            n_trajectories.iter().enumerate().for_each(|(i, t)| {
-                let encoded_trajectory = douglas_peucker(
-                    t.as_slice(),
-                    conf.max_dtw_dist as f64 / 1000.0,
-                    conf.dtw_band,
-                );
-                let cr = t.len() as f64 / encoded_trajectory.len() as f64;
-                if (i + 1) as i32 % (conf.n / log_n) == 0 {
-                    let avg_cr =
-                        encoded_cr.iter().map(|(_, cr)| cr).sum::<f64>() / encoded_cr.len() as f64;
-                    let _file_write_res = write!(
-                        intermediate_file,
-                        "{},{},{},{},{},{},{},{}\n",
-                        match conf.mode.clone() {
-                            Mode::Rest(rest_conf) => {
-                                let mut mode_name = String::from("REST"); // Change to mutable String
-                                if !rest_conf.include_entire_trajectory {
-                                    mode_name.push_str("_EXCL");
-                                }
-                                if rest_conf.spatial_filter {
-                                    mode_name.push_str("-SF"); // Use push_str to append
-                                    mode_name.push_str(&rest_conf.error_point.to_string());
-                                    // Convert error_point to String and append
-                                }
-                                if conf.dtw_band != 0 {
-                                    mode_name.push_str("-BND"); // Append "-BND"
-                                    mode_name.push_str(&conf.dtw_band.to_string());
-                                    // Convert dtw_band to String and append
-                                }
-                                if rest_conf.k != 0 {
-                                    mode_name.push_str("-KNN");
-                                    mode_name.push_str(&rest_conf.k.to_string());
-                                }
-                                mode_name
-                            }
-                            Mode::DP(_) => {
-                                let mut mode_name = String::from("DP");
-                                if conf.dtw_band != 0 {
-                                    mode_name.push_str("-BND"); // Append "-BND"
-                                    mode_name.push_str(&conf.dtw_band.to_string());
-                                    // Convert dtw_band to String and append
-                                }
-                                mode_name
-                            }
-                        },
-                        i + 1,
-                        conf.max_dtw_dist,
-                        0,
-                        0,
-                        format!("{:.0}", begin.elapsed().as_secs_f64()),
-                        format!("{:.2}", avg_cr),
-                        //avg_cr = set_inclusive_cr for DP because there is no overhead
-                        format!("{:.2}", avg_cr),
-                    );
+            for (batch_index, batch) in n_trajectories.chunks(BATCH_SIZE).enumerate() {
+                let simplified = simplify_all(&conf, batch);
+                for (j, (t, encoded_trajectory)) in batch.iter().zip(simplified).enumerate() {
+                    let i = batch_index * BATCH_SIZE + j;
+                    let cr = t.len() as f64 / encoded_trajectory.len() as f64;
+                    if (i + 1) as i32 % log_every == 0 {
+                        let avg_cr = encoded_cr.iter().map(|(_, cr)| cr).sum::<f64>()
+                            / encoded_cr.len() as f64;
+                        let _file_write_res = writeln!(
+                            intermediate_file,
+                            "{},{},{},{:.2},{},{:.0},{:.2},{:.2}",
+                            mode_name(&conf),
+                            i + 1,
+                            conf.max_dtw_dist,
+                            0,
+                            0,
+                            begin.elapsed().as_secs_f64(),
+                            avg_cr,
+                            //avg_cr = set_inclusive_cr for DP because there is no overhead
+                            avg_cr,
+                        );
+                    }
+                    encoded_cr.push((encoded_trajectory, cr));
                 }
-                encoded_cr.push((encoded_trajectory, cr));
-            });
+                if begin.elapsed().as_secs_f64() > TIME_LIMIT {
+                    break;
+                }
+            }
             let avg_cr = encoded_cr.iter().map(|(_, cr)| cr).sum::<f64>() / encoded_cr.len() as f64;
             Ok(PerformanceMetrics {
                 avg_cr,
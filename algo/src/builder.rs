@@ -0,0 +1,786 @@
+use crate::algorithm::{cr_from_shape, Config, RestMode};
+use crate::distance::TrajectoryDistance;
+use crate::metric::PointMetric;
+use crate::reference_set::ReferenceSet;
+use crate::rest::{encode, Point, SubTrajectory};
+use clap::ValueEnum;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Points a trajectory is resampled to for the proxy similarity
+pub const PROXY_POINTS: usize = 16;
+/// Trajectories, itself included, a trajectory needs within the error bound to be a DBSCAN core
+pub const DBSCAN_MIN_POINTS: usize = 3;
+/// Most rounds of k-medoids, which usually settles well before
+pub const KMEDOIDS_ROUNDS: usize = 20;
+/// Grid cells in the sub-paths counted by the segment builder
+pub const SEGMENT_CELLS: usize = 4;
+/// Sample trajectories a sub-path has to pass through to be frequent
+pub const SEGMENT_MIN_SUPPORT: usize = 3;
+
+/// Picks the reference set out of a sample of trajectories
+pub trait ReferenceSetBuilder {
+    /// Builds the set under the parameters of `conf` and `rest_conf`. `on_progress` is
+    /// called with the number of sample trajectories processed and the current set size.
+    fn build(
+        &self,
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample: Vec<Vec<Point>>,
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> ReferenceSet;
+}
+
+/// The builder a run uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BuilderKind {
+    /// One pass over the sample in input order
+    #[default]
+    Greedy,
+    /// The medoids of `set_size` clusters
+    KMedoids,
+    /// The medoids of the `set_size` largest DBSCAN clusters, neighbours within the maximum distance
+    Dbscan,
+    /// Frequently travelled sub-paths of the sample, at most `set_size` of them
+    Segments,
+}
+
+impl BuilderKind {
+    /// The builder, clustering into `set_size` references. The segment builder takes
+    /// `set_size` as a cap, 0 for none.
+    pub fn builder(
+        self,
+        set_size: usize,
+        similarity: SimilarityKind,
+    ) -> Box<dyn ReferenceSetBuilder> {
+        match self {
+            BuilderKind::Greedy => Box::new(Greedy),
+            BuilderKind::KMedoids => Box::new(KMedoids {
+                set_size,
+                similarity,
+            }),
+            BuilderKind::Dbscan => Box::new(Dbscan {
+                set_size,
+                similarity,
+            }),
+            BuilderKind::Segments => Box::new(Segments { set_size }),
+        }
+    }
+
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            BuilderKind::Greedy => "",
+            BuilderKind::KMedoids => "-KMED",
+            BuilderKind::Dbscan => "-DBSCAN",
+            BuilderKind::Segments => "-SEG",
+        }
+    }
+
+    /// Whether the builder picks a fixed number of references and so needs a set size
+    pub fn is_clustering(self) -> bool {
+        matches!(self, BuilderKind::KMedoids | BuilderKind::Dbscan)
+    }
+}
+
+/// How the clustering builders compare two sample trajectories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SimilarityKind {
+    /// The distance of the run, which bounds the error of a piece
+    #[default]
+    Distance,
+    /// The largest distance between the points of both trajectories resampled to
+    /// `PROXY_POINTS` points, which takes constant time
+    Proxy,
+}
+
+impl SimilarityKind {
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            SimilarityKind::Distance => "",
+            SimilarityKind::Proxy => "-PROXY",
+        }
+    }
+}
+
+/// A single pass over the sample in order. Every sample trajectory that compresses worse
+/// than `compression_ratio` against the set so far is added to it, either entirely or
+/// only its uncompressed runs. With a budget, entries are evicted by how the sample
+/// trajectories used them to stay within it.
+pub struct Greedy;
+
+impl ReferenceSetBuilder for Greedy {
+    fn build(
+        &self,
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample: Vec<Vec<Point>>,
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> ReferenceSet {
+        let distance = conf.measure();
+        let mut reference_set = ReferenceSet::new(
+            rest_conf.spatial_filter,
+            conf.point_metric(),
+            rest_conf.budget,
+            rest_conf.eviction,
+        );
+
+        for (i, t) in sample.into_iter().enumerate() {
+            let reference_vec = reference_set
+                .trajectories()
+                .iter()
+                .map(|t| t.as_slice())
+                .collect_vec();
+            let (encoded, shape) = encode(
+                reference_vec.as_slice(),
+                t.as_slice(),
+                conf.max_dtw_dist as f64,
+                distance.as_ref(),
+                rest_conf.k,
+                reference_set.r_tree(),
+                rest_conf.error_point as f64,
+            );
+            let referenced = reference_set.referenced_by(&encoded);
+
+            let mut added = Vec::new();
+            if cr_from_shape(shape) < rest_conf.compression_ratio as f64 {
+                if rest_conf.include_entire_trajectory {
+                    added.push(t);
+                } else {
+                    let mut raw_trajectories_added = Vec::new();
+                    let mut first_point_index = 0;
+                    for st in encoded.0 {
+                        match &st {
+                            SubTrajectory::Trajectory(raw_trajectory) => {
+                                for p in
+                                    raw_trajectory[first_point_index..raw_trajectory.len()].iter()
+                                {
+                                    raw_trajectories_added.push(Some(p.clone()));
+                                }
+                            }
+                            // Successfully compressed, therefore not added to reference set
+                            SubTrajectory::Reference(..) => {
+                                raw_trajectories_added.push(None);
+                            }
+                        }
+                        first_point_index = 1;
+                    }
+                    // Each run of uncompressed points is added as one element
+                    added.extend(
+                        raw_trajectories_added
+                            .split(|item| item.is_none())
+                            .filter(|run| !run.is_empty())
+                            .map(|run| run.iter().flatten().cloned().collect_vec()),
+                    );
+                }
+            }
+            reference_set.record(&referenced);
+            for trajectory in added {
+                reference_set.push(trajectory);
+            }
+
+            on_progress(i + 1, reference_set.len());
+        }
+        reference_set
+    }
+}
+
+/// Clusters the sample into `set_size` clusters around medoids, the trajectories least
+/// dissimilar to the rest of their cluster, and takes the medoids. Starts from the
+/// longest trajectory and spreads the other medoids farthest first, so the set does not
+/// depend on the order of the sample, then moves every medoid to the medoid of its
+/// cluster until none moves.
+pub struct KMedoids {
+    pub set_size: usize,
+    pub similarity: SimilarityKind,
+}
+
+impl ReferenceSetBuilder for KMedoids {
+    fn build(
+        &self,
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample: Vec<Vec<Point>>,
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> ReferenceSet {
+        let dissimilarity = Dissimilarity::new(conf, self.similarity, &sample);
+        let k = self.set_size.min(sample.len());
+        let mut medoids = Vec::new();
+        let mut nearest = vec![f64::INFINITY; sample.len()];
+        while medoids.len() < k {
+            let next = if medoids.is_empty() {
+                (0..sample.len())
+                    .max_by(|&a, &b| by_content(&sample[a], &sample[b]))
+                    .expect("a non-empty sample")
+            } else {
+                (0..sample.len())
+                    .max_by(|&a, &b| {
+                        nearest[a]
+                            .total_cmp(&nearest[b])
+                            .then_with(|| by_content(&sample[a], &sample[b]))
+                    })
+                    .expect("a non-empty sample")
+            };
+            // Only copies of the medoids are left
+            if nearest[next] == 0.0 {
+                break;
+            }
+            for (d, to_next) in nearest.iter_mut().zip(dissimilarity.row(next)) {
+                *d = d.min(to_next);
+            }
+            medoids.push(next);
+        }
+
+        let mut clusters = Vec::new();
+        for _ in 0..KMEDOIDS_ROUNDS {
+            let rows = medoids.iter().map(|&m| dissimilarity.row(m)).collect_vec();
+            clusters = vec![Vec::new(); medoids.len()];
+            for i in 0..sample.len() {
+                let (closest, _) = rows
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a[i].total_cmp(&b[i]))
+                    .expect("k is positive");
+                clusters[closest].push(i);
+            }
+            // A medoid whose copy took its whole cluster stays where it is
+            let moved = clusters
+                .iter()
+                .zip(&medoids)
+                .map(|(cluster, &medoid)| {
+                    if cluster.is_empty() {
+                        medoid
+                    } else {
+                        dissimilarity.medoid(cluster)
+                    }
+                })
+                .collect_vec();
+            if moved == medoids {
+                break;
+            }
+            medoids = moved;
+        }
+
+        let representatives = clusters
+            .iter()
+            .zip(medoids)
+            .sorted_by(|(a, m), (b, n)| {
+                b.len()
+                    .cmp(&a.len())
+                    .then_with(|| by_content(&sample[*n], &sample[*m]))
+            })
+            .map(|(_, medoid)| medoid)
+            .collect_vec();
+        take_representatives(conf, rest_conf, sample, &representatives, on_progress)
+    }
+}
+
+/// Clusters the sample with DBSCAN, with trajectories closer than the error bound as
+/// neighbours, and takes the medoids of the `set_size` largest clusters. When there
+/// are fewer clusters, the set is filled with the trajectories that have the most
+/// neighbours, unclustered ones first, so builders can be compared at the same set size.
+pub struct Dbscan {
+    pub set_size: usize,
+    pub similarity: SimilarityKind,
+}
+
+impl ReferenceSetBuilder for Dbscan {
+    fn build(
+        &self,
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample: Vec<Vec<Point>>,
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> ReferenceSet {
+        let dissimilarity = Dissimilarity::new(conf, self.similarity, &sample);
+        let threshold = conf.max_dtw_dist as f64 / 1000.0;
+        let neighbours = (0..sample.len())
+            .into_par_iter()
+            .map(|i| {
+                (0..sample.len())
+                    .filter(|&j| dissimilarity.within(i, j, threshold))
+                    .collect_vec()
+            })
+            .collect::<Vec<_>>();
+
+        // Clusters grow from the densest cores first, so they do not depend on the order of the sample
+        let mut cluster_of = vec![None; sample.len()];
+        let mut clusters: Vec<Vec<usize>> = Vec::new();
+        let is_core = |i: usize| neighbours[i].len() >= DBSCAN_MIN_POINTS;
+        let densest_first = |&a: &usize, &b: &usize| {
+            neighbours[b]
+                .len()
+                .cmp(&neighbours[a].len())
+                .then_with(|| by_content(&sample[b], &sample[a]))
+        };
+        for seed in (0..sample.len()).sorted_by(densest_first) {
+            if cluster_of[seed].is_some() || !is_core(seed) {
+                continue;
+            }
+            let id = clusters.len();
+            let mut cluster = vec![seed];
+            cluster_of[seed] = Some(id);
+            let mut next = 0;
+            while next < cluster.len() {
+                let i = cluster[next];
+                next += 1;
+                if !is_core(i) {
+                    continue;
+                }
+                for &j in &neighbours[i] {
+                    if cluster_of[j].is_none() {
+                        cluster_of[j] = Some(id);
+                        cluster.push(j);
+                    }
+                }
+            }
+            clusters.push(cluster);
+        }
+
+        let mut representatives = clusters
+            .iter()
+            .map(|cluster| dissimilarity.medoid(cluster))
+            .sorted_by(|&a, &b| {
+                let size = |m: usize| clusters[cluster_of[m].expect("medoids are clustered")].len();
+                size(b)
+                    .cmp(&size(a))
+                    .then_with(|| by_content(&sample[b], &sample[a]))
+            })
+            .take(self.set_size)
+            .collect_vec();
+        let rest = (0..sample.len())
+            .filter(|i| !representatives.contains(i))
+            .sorted_by(|a, b| {
+                cluster_of[*a]
+                    .is_some()
+                    .cmp(&cluster_of[*b].is_some())
+                    .then_with(|| densest_first(a, b))
+            })
+            .take(self.set_size - representatives.len())
+            .collect_vec();
+        representatives.extend(rest);
+        take_representatives(conf, rest_conf, sample, &representatives, on_progress)
+    }
+}
+
+/// Mines the sub-paths the sample travels most and stores them as reference segments
+/// rather than whole trajectories. Positions are snapped to a grid of cells the size of
+/// the error bound, and every run of `SEGMENT_CELLS` cells that at least
+/// `SEGMENT_MIN_SUPPORT` sample trajectories pass through is frequent. The overlapping
+/// frequent runs of a trajectory are joined into one candidate segment, and candidates
+/// are taken most travelled first when they compress worse than `compression_ratio`
+/// against the segments taken so far, up to `set_size` segments unless it is 0.
+pub struct Segments {
+    pub set_size: usize,
+}
+
+impl ReferenceSetBuilder for Segments {
+    fn build(
+        &self,
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample: Vec<Vec<Point>>,
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> ReferenceSet {
+        let grid = Grid::fit(&sample, conf.point_metric(), conf.max_dtw_dist);
+        let paths = sample.iter().map(|t| grid.path(t)).collect_vec();
+        let mut support: HashMap<&[(i32, i32)], usize> = HashMap::new();
+        for (cells, _) in &paths {
+            for window in cells.windows(SEGMENT_CELLS).unique() {
+                *support.entry(window).or_default() += 1;
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for (t, (cells, starts)) in sample.iter().zip(&paths) {
+            let windows = cells
+                .windows(SEGMENT_CELLS)
+                .map(|window| support[window])
+                .collect_vec();
+            let mut first = 0;
+            while first < windows.len() {
+                if windows[first] < SEGMENT_MIN_SUPPORT {
+                    first += 1;
+                    continue;
+                }
+                let mut last = first;
+                while last + 1 < windows.len() && windows[last + 1] >= SEGMENT_MIN_SUPPORT {
+                    last += 1;
+                }
+                // The segment ends on the first point of the cell after the run, so it
+                // reaches across the boundary the way the trajectory does
+                let end = starts
+                    .get(last + SEGMENT_CELLS)
+                    .map_or(t.len(), |&next| next + 1);
+                let travelled = windows[first..=last].iter().sum::<usize>();
+                candidates.push((travelled, t[starts[first]..end].to_vec()));
+                first = last + 1;
+            }
+        }
+        candidates.sort_by(|(a, t), (b, u)| b.cmp(a).then_with(|| by_content(u, t)));
+        candidates.dedup_by(|(_, t), (_, u)| t == u);
+
+        let distance = conf.measure();
+        let mut reference_set = ReferenceSet::new(
+            rest_conf.spatial_filter,
+            conf.point_metric(),
+            rest_conf.budget,
+            rest_conf.eviction,
+        );
+        for (_, segment) in candidates {
+            if self.set_size != 0 && reference_set.len() >= self.set_size {
+                break;
+            }
+            let reference_vec = reference_set
+                .trajectories()
+                .iter()
+                .map(|t| t.as_slice())
+                .collect_vec();
+            let (_, shape) = encode(
+                reference_vec.as_slice(),
+                segment.as_slice(),
+                conf.max_dtw_dist as f64,
+                distance.as_ref(),
+                rest_conf.k,
+                reference_set.r_tree(),
+                rest_conf.error_point as f64,
+            );
+            if cr_from_shape(shape) < rest_conf.compression_ratio as f64 {
+                reference_set.push(segment);
+            }
+        }
+        on_progress(sample.len(), reference_set.len());
+        reference_set
+    }
+}
+
+/// A grid of cells the size of the error bound, laid over the middle of a sample
+struct Grid {
+    lat_side: i32,
+    lng_side: i32,
+}
+
+impl Grid {
+    fn fit(sample: &[Vec<Point>], metric: PointMetric, max_dtw_dist: i32) -> Grid {
+        let points = sample.iter().flatten();
+        let center = match (
+            points.clone().map(|p| p.lat).minmax().into_option(),
+            points.map(|p| p.lng).minmax().into_option(),
+        ) {
+            (Some((lat_min, lat_max)), Some((lng_min, lng_max))) => Point {
+                lat: lat_min + (lat_max - lat_min) / 2,
+                lng: lng_min + (lng_max - lng_min) / 2,
+                time: None,
+            },
+            _ => Point {
+                lat: 0,
+                lng: 0,
+                time: None,
+            },
+        };
+        let (lat_d, lng_d) = metric.half_extent(&center, max_dtw_dist as f64 / 1000.0 / 2.0);
+        Grid {
+            lat_side: ((2.0 * lat_d).round() as i32).max(1),
+            lng_side: ((2.0 * lng_d).round() as i32).max(1),
+        }
+    }
+
+    /// The cells a trajectory passes through, a cell again only after leaving it, with
+    /// the index of the point it enters each at
+    fn path(&self, trajectory: &[Point]) -> (Vec<(i32, i32)>, Vec<usize>) {
+        let mut cells = Vec::new();
+        let mut starts = Vec::new();
+        for (i, p) in trajectory.iter().enumerate() {
+            let cell = (
+                p.lat.div_euclid(self.lat_side),
+                p.lng.div_euclid(self.lng_side),
+            );
+            if cells.last() != Some(&cell) {
+                cells.push(cell);
+                starts.push(i);
+            }
+        }
+        (cells, starts)
+    }
+}
+
+/// A set of the sample trajectories at `representatives`, in that order
+fn take_representatives(
+    conf: &Config,
+    rest_conf: &RestMode,
+    sample: Vec<Vec<Point>>,
+    representatives: &[usize],
+    on_progress: &mut dyn FnMut(usize, usize),
+) -> ReferenceSet {
+    let processed = sample.len();
+    let mut sample = sample.into_iter().map(Some).collect_vec();
+    let mut reference_set = ReferenceSet::new(
+        rest_conf.spatial_filter,
+        conf.point_metric(),
+        rest_conf.budget,
+        rest_conf.eviction,
+    );
+    for &i in representatives {
+        reference_set.push(sample[i].take().expect("representatives are distinct"));
+    }
+    on_progress(processed, reference_set.len());
+    reference_set
+}
+
+/// Dissimilarities between the trajectories of a sample
+struct Dissimilarity<'a> {
+    sample: &'a [Vec<Point>],
+    distance: Box<dyn TrajectoryDistance>,
+    /// The sample resampled for the proxy similarity
+    proxies: Option<Vec<Vec<Point>>>,
+}
+
+impl<'a> Dissimilarity<'a> {
+    fn new(
+        conf: &Config,
+        similarity: SimilarityKind,
+        sample: &'a [Vec<Point>],
+    ) -> Dissimilarity<'a> {
+        Dissimilarity {
+            sample,
+            distance: conf.measure(),
+            proxies: match similarity {
+                SimilarityKind::Distance => None,
+                SimilarityKind::Proxy => Some(sample.iter().map(|t| resample(t)).collect()),
+            },
+        }
+    }
+
+    fn between(&self, i: usize, j: usize) -> f64 {
+        match &self.proxies {
+            Some(proxies) => proxy_distance(&proxies[i], &proxies[j], self.distance.metric()),
+            None => self.distance.distance(&self.sample[i], &self.sample[j]),
+        }
+    }
+
+    /// Whether trajectories `i` and `j` are closer than `threshold`
+    fn within(&self, i: usize, j: usize, threshold: f64) -> bool {
+        let bounded = self.proxies.is_none()
+            && self
+                .distance
+                .lower_bound_reaches(&self.sample[i], &self.sample[j], threshold);
+        !bounded && self.between(i, j) < threshold
+    }
+
+    /// The dissimilarities of trajectory `i` to every trajectory of the sample
+    fn row(&self, i: usize) -> Vec<f64> {
+        (0..self.sample.len())
+            .into_par_iter()
+            .map(|j| self.between(i, j))
+            .collect()
+    }
+
+    /// The member of `cluster` with the least total dissimilarity to the others
+    fn medoid(&self, cluster: &[usize]) -> usize {
+        cluster
+            .par_iter()
+            .map(|&i| (cluster.iter().map(|&j| self.between(i, j)).sum::<f64>(), i))
+            .min_by(|a, b| {
+                a.0.total_cmp(&b.0)
+                    .then_with(|| by_content(&self.sample[a.1], &self.sample[b.1]))
+            })
+            .expect("clusters are not empty")
+            .1
+    }
+}
+
+/// Orders trajectories by length, then by their points, which breaks ties between
+/// trajectories without depending on where they are in the sample
+fn by_content(a: &[Point], b: &[Point]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| {
+        a.iter()
+            .map(|p| (p.lat, p.lng))
+            .cmp(b.iter().map(|p| (p.lat, p.lng)))
+    })
+}
+
+/// `trajectory` at `PROXY_POINTS` points spread evenly over its indices
+fn resample(trajectory: &[Point]) -> Vec<Point> {
+    match trajectory.len() {
+        0 => Vec::new(),
+        len => (0..PROXY_POINTS)
+            .map(|i| trajectory[i * (len - 1) / (PROXY_POINTS - 1)].clone())
+            .collect(),
+    }
+}
+
+/// The largest distance between the points of two resampled trajectories, taken in order
+fn proxy_distance(a: &[Point], b: &[Point], metric: PointMetric) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(p, q)| metric.distance(p, q))
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::Mode;
+    use crate::distance::DistanceKind;
+    use crate::projection::ProjectionKind;
+    use crate::reference_set::EvictionPolicy;
+    use crate::sampling::Sampling;
+    use crate::source::tests::sample_points;
+
+    /// A REST run over sample.csv with a 1 km error bound that builds its set with `builder`
+    fn config_with(builder: BuilderKind, set_size: Option<usize>) -> (Config, RestMode) {
+        let rest_conf = RestMode {
+            rs: 1000,
+            compression_ratio: 3,
+            spatial_filter: true,
+            include_entire_trajectory: true,
+            k: 3,
+            error_point: 70,
+            budget: None,
+            eviction: EvictionPolicy::default(),
+            builder,
+            set_size,
+            similarity: SimilarityKind::Distance,
+        };
+        let conf = Config {
+            n: 29,
+            max_dtw_dist: 1000,
+            dtw_band: 0,
+            distance: DistanceKind::MaxDtw,
+            metric: PointMetric::Haversine,
+            projection: ProjectionKind::None,
+            sampling: Sampling::Head,
+            seed: 0,
+            mode: Mode::Rest(rest_conf),
+        };
+        (conf, rest_conf)
+    }
+
+    /// The set the run builds out of `sample`, with the builder it asks for
+    fn build(conf: &Config, rest_conf: &RestMode, sample: Vec<Vec<Point>>) -> ReferenceSet {
+        rest_conf
+            .builder
+            .builder(rest_conf.set_size.unwrap_or(0), rest_conf.similarity)
+            .build(conf, rest_conf, sample, &mut |_, _| {})
+    }
+
+    fn reversed(sample: &[Vec<Point>]) -> Vec<Vec<Point>> {
+        sample.iter().rev().cloned().collect_vec()
+    }
+
+    #[test]
+    fn test_kmedoids_ignores_sample_order() {
+        let sample = sample_points();
+        let (conf, mut rest_conf) = config_with(BuilderKind::KMedoids, Some(8));
+        for similarity in [SimilarityKind::Distance, SimilarityKind::Proxy] {
+            rest_conf.similarity = similarity;
+            let set = build(&conf, &rest_conf, sample.clone());
+            assert_eq!(set.len(), 8);
+            assert!(set.trajectories().iter().all(|t| sample.contains(t)));
+            let reversed_set = build(&conf, &rest_conf, reversed(&sample));
+            assert_eq!(set.trajectories(), reversed_set.trajectories());
+        }
+    }
+
+    #[test]
+    fn test_clustering_builders_report_progress_once() {
+        let sample = sample_points();
+        for kind in [BuilderKind::KMedoids, BuilderKind::Dbscan] {
+            let (conf, rest_conf) = config_with(kind, Some(8));
+            let mut progress = Vec::new();
+            let set = kind.builder(8, SimilarityKind::Distance).build(
+                &conf,
+                &rest_conf,
+                sample.clone(),
+                &mut |i, len| progress.push((i, len)),
+            );
+            assert_eq!(progress, vec![(sample.len(), 8)]);
+            assert_eq!(set.r_tree().unwrap().size(), set.points());
+        }
+    }
+
+    #[test]
+    fn test_dbscan_fills_up_to_set_size() {
+        let sample = sample_points();
+        // Clusters have at least DBSCAN_MIN_POINTS trajectories, so there are fewer than 20
+        assert!(sample.len() / DBSCAN_MIN_POINTS < 20);
+        for set_size in [8, 20] {
+            let (conf, mut rest_conf) = config_with(BuilderKind::Dbscan, Some(set_size));
+            for similarity in [SimilarityKind::Distance, SimilarityKind::Proxy] {
+                rest_conf.similarity = similarity;
+                let set = build(&conf, &rest_conf, sample.clone());
+                assert_eq!(set.len(), set_size);
+                assert!(set.trajectories().iter().all(|t| sample.contains(t)));
+                assert!(set.trajectories().iter().all_unique());
+                let reversed_set = build(&conf, &rest_conf, reversed(&sample));
+                assert_eq!(set.trajectories(), reversed_set.trajectories());
+            }
+        }
+    }
+
+    #[test]
+    fn test_proxy_similarity_compares_resampled_trajectories() {
+        let sample = sample_points();
+        let (conf, _) = config_with(BuilderKind::Dbscan, None);
+        let metric = conf.point_metric();
+        let distance = Dissimilarity::new(&conf, SimilarityKind::Distance, &sample);
+        let proxy = Dissimilarity::new(&conf, SimilarityKind::Proxy, &sample);
+        for (i, j) in [(0, 1), (3, 7), (12, 28)] {
+            assert_eq!(
+                distance.between(i, j),
+                conf.measure().distance(&sample[i], &sample[j])
+            );
+            let (a, b) = (resample(&sample[i]), resample(&sample[j]));
+            assert_eq!(a.len(), PROXY_POINTS);
+            assert_eq!((a.first(), a.last()), (sample[i].first(), sample[i].last()));
+            assert_eq!(proxy.between(i, j), proxy_distance(&a, &b, metric));
+            assert_eq!(proxy.between(i, j), proxy.between(j, i));
+            assert_eq!(proxy.between(i, i), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_segment_builder_keeps_shared_sub_paths() {
+        let point = |lat: i32, lng: i32| Point {
+            lat,
+            lng,
+            time: None,
+        };
+        let shared = (0..20)
+            .map(|i| point(41_150_000 + i * 1000, -8_610_000))
+            .collect_vec();
+        // Trips that only share the middle of their path, coming and going in different directions
+        let sample = (1..5)
+            .map(|k| {
+                let away = |i: i32| point(41_150_000 + i * 1000, -8_610_000 + k * 20_000 + i * 500);
+                (-5..0)
+                    .map(away)
+                    .chain(shared.iter().cloned())
+                    .chain((20..25).map(away))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let (mut conf, rest_conf) = config_with(BuilderKind::Segments, None);
+        conf.max_dtw_dist = 200;
+
+        let set = build(&conf, &rest_conf, sample.clone());
+        assert_eq!(set.len(), 1);
+        let segment = &set.trajectories()[0];
+        assert!(segment.len() < sample[0].len());
+        assert!(shared.iter().all(|p| segment.contains(p)));
+        assert!(sample
+            .iter()
+            .any(|t| t.windows(segment.len()).any(|w| w == segment.as_slice())));
+        let reversed_set = build(&conf, &rest_conf, reversed(&sample));
+        assert_eq!(set.trajectories(), reversed_set.trajectories());
+        // Nothing is travelled often enough without a third trip
+        let pair = build(&conf, &rest_conf, sample[..2].to_vec());
+        assert!(pair.is_empty());
+    }
+}
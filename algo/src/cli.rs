@@ -0,0 +1,764 @@
+use crate::algorithm::{
+    build_reference_set, encode_all, mode_name, read_trajectories, reference_sample_size,
+    rest_main, simplify_all, Config, DpMode, Mode, PerformanceMetrics, RestMode,
+};
+use crate::builder::{BuilderKind, SimilarityKind};
+use crate::container::{
+    CompressedTrajectory, ContainerReader, ContainerWriter, Segment, StoredSubTrajectory,
+};
+use crate::distance::DistanceKind;
+use crate::dp::Simplifier;
+use crate::error::Error;
+use crate::flatbuffer::{write_flatbuffer, FlatBufferSource};
+use crate::json::{segment_features, trajectory_feature, write_geojson, write_json, JsonSource};
+use crate::metric::{approximation_error, mean_latitude, PointMetric};
+use crate::projection::ProjectionKind;
+use crate::reference_set::{
+    load_reference_set, read_reference_set, save_reference_set, EvictionPolicy,
+    ReferenceSetMetadata,
+};
+use crate::sampling::Sampling;
+use crate::source::{
+    ColumnMapping, CoordinateOrder, CsvSource, SourceTrajectory, TrajectorySource,
+};
+use crate::sweep::load_sweep;
+use crate::temporal::is_timed;
+use crate::usage::UsageFormat;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use itertools::Itertools;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+#[command(about = "REST and Douglas-Peucker trajectory compression")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /// Number of threads used to compress, all cores when omitted
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Build a reference set from rs/1000 * n trajectories of the input, drawn by --sampling, and save it
+    BuildReference {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Compress n trajectories of the input into a container file
+    Compress {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// Reference set saved by build-reference. When omitted, one is built from the input
+        /// and saved next to the output with the `rstr` extension, for decompress.
+        #[arg(long)]
+        reference: Option<PathBuf>,
+        /// Number of input trajectories to skip before compressing
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Decode a container file to a CSV with a polyline column
+    Decompress {
+        #[arg(long)]
+        input: PathBuf,
+        /// Reference set the container was compressed against, not needed for DP
+        #[arg(long)]
+        reference: Option<PathBuf>,
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = FormatArg::Csv)]
+        output_format: FormatArg,
+        /// Axis order of the positions written to CSV
+        #[arg(long, value_enum, default_value_t = CoordinateOrder::LngLat)]
+        coordinate_order: CoordinateOrder,
+        /// Write each raw and reference segment as its own GeoJSON feature
+        #[arg(long)]
+        tag_segments: bool,
+    },
+    /// Run one experiment and append its progress to set_size.txt and intermediate.txt
+    Evaluate {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        #[command(flatten)]
+        logging: LoggingArgs,
+        /// Stop after building the reference set
+        #[arg(long)]
+        only_set: bool,
+    },
+    /// Run the experiment once per reference set size
+    Bench {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        #[command(flatten)]
+        logging: LoggingArgs,
+        /// Reference set sizes in thousandths of n, replacing --rs
+        #[arg(long, value_delimiter = ',', required = true)]
+        rs_values: Vec<i32>,
+    },
+    /// Convert a dataset to another format, by default a flatbuffer that loads without parsing
+    Convert {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = FormatArg::Flatbuffers)]
+        to: FormatArg,
+    },
+    /// Print how far each point metric strays from haversine over n trajectories, as CSV
+    MetricError {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[arg(long, default_value_t = 1000)]
+        n: usize,
+        /// Standard parallel of the planar metric, the mean latitude of the points when omitted
+        #[arg(long)]
+        planar_latitude: Option<f64>,
+    },
+    /// Run every config of a TOML sweep file, validating all of them first
+    Sweep {
+        #[arg(long, default_value = "config.toml")]
+        config: PathBuf,
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        logging: LoggingArgs,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ModeArg {
+    Rest,
+    Dp,
+    /// Douglas-Peucker with a per-point stopping rule
+    DpPoint,
+    /// Top-down time-ratio
+    TdTr,
+    /// Visvalingam-Whyatt
+    Vw,
+    /// Opening Window, online
+    Opw,
+    /// SQUISH-E, online
+    SquishE,
+    /// Dead Reckoning, online
+    Dr,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum MetricArg {
+    Haversine,
+    Equirectangular,
+    Planar,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum FormatArg {
+    Csv,
+    Flatbuffers,
+    Json,
+    Geojson,
+}
+
+#[derive(Args, Debug)]
+pub struct SourceArgs {
+    /// Dataset with one trajectory per row, flatbuffer entry or JSON object
+    #[arg(long, default_value = "porto.csv")]
+    pub input: PathBuf,
+    #[arg(long, value_enum, default_value_t = FormatArg::Csv)]
+    pub format: FormatArg,
+    #[arg(long)]
+    pub id_column: Option<String>,
+    /// Column holding the trajectory as a JSON array of coordinate pairs
+    #[arg(long, default_value = "polyline")]
+    pub polyline_column: String,
+    /// Column holding the start time in seconds since the Unix epoch
+    #[arg(long)]
+    pub timestamp_column: Option<String>,
+    /// Seconds between two points, which times every point from the start time. Porto samples every 15 s.
+    #[arg(long)]
+    pub sampling_interval: Option<i64>,
+    /// Axis order of the positions, Porto stores lng-lat
+    #[arg(long, value_enum, default_value_t = CoordinateOrder::LngLat)]
+    pub coordinate_order: CoordinateOrder,
+}
+
+impl SourceArgs {
+    pub fn source(&self) -> Result<Box<dyn TrajectorySource>, Error> {
+        Ok(match self.format {
+            FormatArg::Csv => Box::new(CsvSource {
+                path: self.input.clone(),
+                columns: ColumnMapping {
+                    id: self.id_column.clone(),
+                    polyline: self.polyline_column.clone(),
+                    timestamp: self.timestamp_column.clone(),
+                },
+                coordinate_order: self.coordinate_order,
+                sampling_interval: self.sampling_interval,
+            }),
+            FormatArg::Flatbuffers => Box::new(FlatBufferSource::open(&self.input)?),
+            FormatArg::Json | FormatArg::Geojson => Box::new(JsonSource {
+                path: self.input.clone(),
+                geojson: matches!(self.format, FormatArg::Geojson),
+            }),
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ExperimentArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+    #[arg(long, value_enum, default_value_t = ModeArg::Rest)]
+    pub mode: ModeArg,
+    /// Number of trajectories to compress
+    #[arg(long, default_value_t = 100000)]
+    pub n: i32,
+    /// Maximum DTW distance in meters
+    #[arg(long, default_value_t = 200)]
+    pub max_dtw_dist: i32,
+    #[arg(long, default_value_t = 0)]
+    pub dtw_band: usize,
+    /// Distance bounding the error of each compressed piece
+    #[arg(long, value_enum, default_value_t = DistanceKind::MaxDtw)]
+    pub distance: DistanceKind,
+    /// Distance between two points
+    #[arg(long, value_enum, default_value_t = MetricArg::Haversine)]
+    pub metric: MetricArg,
+    /// Standard parallel of the planar metric in degrees
+    #[arg(long)]
+    pub planar_latitude: Option<f64>,
+    /// Projection points are compressed in, fitted to the centroid of the input
+    #[arg(long, value_enum, default_value_t = ProjectionKind::None)]
+    pub projection: ProjectionKind,
+    /// How the reference sample and the trajectories to compress are drawn from the input
+    #[arg(long, value_enum, default_value_t = Sampling::Head)]
+    pub sampling: Sampling,
+    /// Seed of the random --sampling modes
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Reference set size in thousandths of n
+    #[arg(long, default_value_t = 100)]
+    pub rs: i32,
+    #[arg(long, default_value_t = 3)]
+    pub compression_ratio: i32,
+    /// Candidates kept per expansion step, 0 keeps all
+    #[arg(long, default_value_t = 3)]
+    pub k: usize,
+    /// Spatial filter envelope in meters
+    #[arg(long, default_value_t = 70)]
+    pub error_point: i32,
+    #[arg(long)]
+    pub no_spatial_filter: bool,
+    /// Only add the uncompressed runs of a sample trajectory to the reference set
+    #[arg(long)]
+    pub exclude_compressed: bool,
+    /// Most points the reference set holds while it is built, evicting entries past it
+    #[arg(long)]
+    pub budget: Option<usize>,
+    /// Which entries are evicted to stay within --budget
+    #[arg(long, value_enum, default_value_t = EvictionPolicy::LeastRecentlyUsed)]
+    pub eviction: EvictionPolicy,
+    /// How the reference set is picked from the sample
+    #[arg(long, value_enum, default_value_t = BuilderKind::Greedy)]
+    pub builder: BuilderKind,
+    /// Number of references the clustering builders pick, and most the segment builder does
+    #[arg(long)]
+    pub set_size: Option<usize>,
+    /// How the clustering builders compare trajectories
+    #[arg(long, value_enum, default_value_t = SimilarityKind::Distance)]
+    pub similarity: SimilarityKind,
+}
+
+#[derive(Args, Debug)]
+pub struct LoggingArgs {
+    /// Directory set_size.txt, intermediate.txt and the reports are appended to
+    #[arg(long, default_value = "out")]
+    pub out_dir: PathBuf,
+    /// Number of progress lines written per run
+    #[arg(long, default_value_t = 1)]
+    pub log_n: i32,
+    /// Also append how much each reference set entry was used to reference_usage.csv or .jsonl
+    #[arg(long, value_enum)]
+    pub usage_report: Option<UsageFormat>,
+    /// Also append how much of the compressed trajectories the reference set covers to coverage.txt
+    #[arg(long)]
+    pub coverage_report: bool,
+}
+
+impl ExperimentArgs {
+    fn metric(&self) -> Result<PointMetric, Error> {
+        match (self.metric, self.planar_latitude) {
+            (MetricArg::Haversine, _) => Ok(PointMetric::Haversine),
+            (MetricArg::Equirectangular, _) => Ok(PointMetric::Equirectangular),
+            (MetricArg::Planar, Some(latitude)) => Ok(PointMetric::Planar { latitude }),
+            (MetricArg::Planar, None) => Err(Error::Config(String::from(
+                "the planar metric needs --planar-latitude",
+            ))),
+        }
+    }
+
+    pub fn config(&self) -> Result<Config, Error> {
+        let conf = Config {
+            n: self.n,
+            max_dtw_dist: self.max_dtw_dist,
+            dtw_band: self.dtw_band,
+            distance: self.distance,
+            metric: self.metric()?,
+            projection: self.projection,
+            sampling: self.sampling,
+            seed: self.seed,
+            mode: match self.mode {
+                ModeArg::Rest => Mode::Rest(RestMode {
+                    rs: self.rs,
+                    compression_ratio: self.compression_ratio,
+                    spatial_filter: !self.no_spatial_filter,
+                    include_entire_trajectory: !self.exclude_compressed,
+                    k: self.k,
+                    error_point: self.error_point,
+                    budget: self.budget,
+                    eviction: self.eviction,
+                    builder: self.builder,
+                    set_size: self.set_size,
+                    similarity: self.similarity,
+                }),
+                ModeArg::Dp => Mode::DP(DpMode::default()),
+                ModeArg::DpPoint => Mode::DP(DpMode {
+                    simplifier: Simplifier::DpPoint,
+                }),
+                ModeArg::TdTr => Mode::DP(DpMode {
+                    simplifier: Simplifier::TdTr,
+                }),
+                ModeArg::Vw => Mode::DP(DpMode {
+                    simplifier: Simplifier::Visvalingam,
+                }),
+                ModeArg::Opw => Mode::DP(DpMode {
+                    simplifier: Simplifier::OpeningWindow,
+                }),
+                ModeArg::SquishE => Mode::DP(DpMode {
+                    simplifier: Simplifier::SquishE,
+                }),
+                ModeArg::Dr => Mode::DP(DpMode {
+                    simplifier: Simplifier::DeadReckoning,
+                }),
+            },
+        };
+        let problems = conf.validate();
+        if !problems.is_empty() {
+            return Err(Error::Config(problems.join(", ")));
+        }
+        Ok(conf)
+    }
+}
+
+fn rest_conf(conf: &Config) -> Result<RestMode, Error> {
+    match conf.mode {
+        Mode::Rest(rest_conf) => Ok(rest_conf),
+        Mode::DP(_) => Err(Error::Config(String::from(
+            "a reference set is only used in REST mode",
+        ))),
+    }
+}
+
+fn print_metrics(conf: &Config, metrics: &PerformanceMetrics) {
+    println!(
+        "{},{},{},{:.2},{:.0}",
+        mode_name(conf),
+        metrics.max_dtw_dist,
+        metrics.set_size,
+        metrics.avg_cr,
+        metrics.runtime.as_secs_f64()
+    );
+}
+
+/// Writes trajectories in any of the supported formats. `segments` splits each
+/// trajectory into raw and reference features in GeoJSON and is ignored otherwise.
+fn write_trajectories(
+    trajectories: &[SourceTrajectory],
+    segments: Option<&[Vec<Segment>]>,
+    format: FormatArg,
+    order: CoordinateOrder,
+    output: &Path,
+) -> Result<(), Error> {
+    match format {
+        FormatArg::Csv => {
+            // Times go in their own column when every point has one
+            let timed = trajectories.iter().all(|t| is_timed(&t.points));
+            let mut writer = csv::Writer::from_path(output)?;
+            let header = ["id", "polyline", "timestamps"];
+            writer.write_record(&header[..if timed { 3 } else { 2 }])?;
+            for (i, trajectory) in trajectories.iter().enumerate() {
+                let polyline = trajectory
+                    .points
+                    .iter()
+                    .map(|p| order.position(p))
+                    .collect_vec();
+                let mut record = vec![
+                    trajectory.id.clone().unwrap_or_else(|| (i + 1).to_string()),
+                    serde_json::to_string(&polyline)?,
+                ];
+                if timed {
+                    let times = trajectory.points.iter().map(|p| p.time).collect_vec();
+                    record.push(serde_json::to_string(&times)?);
+                }
+                writer.write_record(record)?;
+            }
+            writer.flush()?;
+        }
+        FormatArg::Flatbuffers => {
+            write_flatbuffer(
+                trajectories.iter().cloned().map(Ok),
+                BufWriter::new(File::create(output)?),
+            )?;
+        }
+        FormatArg::Json => write_json(trajectories, BufWriter::new(File::create(output)?))?,
+        FormatArg::Geojson => {
+            let features = match segments {
+                Some(segments) => trajectories
+                    .iter()
+                    .zip(segments)
+                    .flat_map(|(t, s)| segment_features(t.id.as_deref(), &t.points, s.clone()))
+                    .collect(),
+                None => trajectories
+                    .iter()
+                    .map(|t| trajectory_feature(t.id.as_deref(), &t.points))
+                    .collect(),
+            };
+            write_geojson(features, BufWriter::new(File::create(output)?))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(cli: Cli) -> Result<(), Error> {
+    if let Some(threads) = cli.threads {
+        if threads == 0 {
+            return Err(Error::Config(String::from("threads must be positive")));
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| Error::Config(e.to_string()))?;
+    }
+    match cli.command {
+        Command::BuildReference { experiment, output } => {
+            let conf = experiment.config()?;
+            let source = experiment.source.source()?;
+            let rest_conf = rest_conf(&conf)?;
+            let sample_size = reference_sample_size(&conf, &rest_conf);
+            let (sample, _) = conf
+                .sampling
+                .draw(source.as_ref(), conf.seed, sample_size, 0)?;
+            let projection = conf.projection.fit(&sample);
+            let (reference_set, _, raw_points) =
+                build_reference_set(&conf, &rest_conf, projection.project_all(sample), |_, _| {});
+            let reference_set = projection.unproject_all(reference_set);
+            save_reference_set(
+                &output,
+                &ReferenceSetMetadata::new(&conf, &rest_conf, sample_size, projection),
+                &reference_set,
+            )?;
+            println!(
+                "{} reference trajectories with {} points written to {}",
+                reference_set.len(),
+                raw_points,
+                output.display()
+            );
+        }
+        Command::Compress {
+            experiment,
+            reference,
+            skip,
+            output,
+        } => {
+            let conf = experiment.config()?;
+            let source = experiment.source.source()?;
+            let trajectories = read_trajectories(source.as_ref(), skip, conf.n as usize)?;
+            let raw_bytes = trajectories
+                .iter()
+                .map(|t| t.len() * if is_timed(t) { 16 } else { 8 })
+                .sum::<usize>();
+            let (mut compressed, projection) = match conf.mode {
+                Mode::Rest(rest_conf) => {
+                    let sample_size = reference_sample_size(&conf, &rest_conf);
+                    let (reference_set, r_tree, projection) = match reference {
+                        // The projection is read from the set, only its kind is compared
+                        Some(path) => load_reference_set(
+                            path,
+                            &ReferenceSetMetadata::new(
+                                &conf,
+                                &rest_conf,
+                                sample_size,
+                                conf.projection.fit(&[]),
+                            ),
+                        )?,
+                        None => {
+                            let (sample, _) =
+                                conf.sampling
+                                    .draw(source.as_ref(), conf.seed, sample_size, 0)?;
+                            let projection = conf.projection.fit(&sample);
+                            let (reference_set, r_tree, _) = build_reference_set(
+                                &conf,
+                                &rest_conf,
+                                projection.project_all(sample),
+                                |_, _| {},
+                            );
+                            // The container is only readable with the set it was compressed against
+                            let path = output.with_extension("rstr");
+                            save_reference_set(
+                                &path,
+                                &ReferenceSetMetadata::new(
+                                    &conf,
+                                    &rest_conf,
+                                    sample_size,
+                                    projection.clone(),
+                                ),
+                                &projection.unproject_all(reference_set.clone()),
+                            )?;
+                            println!("reference set written to {}", path.display());
+                            (reference_set, r_tree, projection)
+                        }
+                    };
+                    let trajectories = projection.project_all(trajectories);
+                    let reference_vectors =
+                        reference_set.iter().map(|t| t.as_slice()).collect_vec();
+                    let compressed = encode_all(
+                        &conf,
+                        &rest_conf,
+                        &reference_vectors,
+                        r_tree.as_ref(),
+                        &trajectories,
+                    )
+                    .iter()
+                    .map(|(encoded, _)| {
+                        CompressedTrajectory::from_encoded(encoded, &reference_vectors)
+                            .expect("encoded references lie in the reference set")
+                    })
+                    .collect_vec();
+                    (compressed, projection)
+                }
+                Mode::DP(_) => {
+                    let projection = conf.projection.fit(&trajectories);
+                    let compressed = simplify_all(&conf, &projection.project_all(trajectories))
+                        .into_iter()
+                        .map(|t| CompressedTrajectory(vec![StoredSubTrajectory::Trajectory(t)]))
+                        .collect_vec();
+                    (compressed, projection)
+                }
+            };
+            // Containers hold lat/lng, references are resolved against the stored set
+            let unproject = projection.unprojector();
+            for trajectory in compressed.iter_mut() {
+                trajectory.map_raw_points(&unproject);
+            }
+
+            let mut writer = ContainerWriter::new(BufWriter::new(File::create(&output)?))?;
+            for trajectory in &compressed {
+                writer.write(trajectory)?;
+            }
+            let bytes_written = writer.bytes_written();
+            writer.finish()?;
+            println!(
+                "{} trajectories, {} raw bytes, {} compressed bytes, cr {:.2}",
+                compressed.len(),
+                raw_bytes,
+                bytes_written,
+                raw_bytes as f64 / bytes_written as f64
+            );
+        }
+        Command::Decompress {
+            input,
+            reference,
+            output,
+            output_format,
+            coordinate_order,
+            tag_segments,
+        } => {
+            let reference_set = match reference {
+                Some(path) => read_reference_set(BufReader::new(File::open(path)?))?.1,
+                None => Vec::new(),
+            };
+            let reference_vectors = reference_set.iter().map(|t| t.as_slice()).collect_vec();
+            let mut trajectories = Vec::new();
+            let mut segments = Vec::new();
+            for (id, trajectory) in
+                ContainerReader::new(BufReader::new(File::open(&input)?))?.enumerate()
+            {
+                let (points, trajectory_segments) =
+                    trajectory?.decode_segments(&reference_vectors)?;
+                trajectories.push(SourceTrajectory {
+                    id: Some((id + 1).to_string()),
+                    timestamp: points.first().and_then(|p| p.time),
+                    points,
+                });
+                segments.push(trajectory_segments);
+            }
+            write_trajectories(
+                &trajectories,
+                tag_segments.then_some(segments.as_slice()),
+                output_format,
+                coordinate_order,
+                &output,
+            )?;
+        }
+        Command::Evaluate {
+            experiment,
+            logging,
+            only_set,
+        } => {
+            let conf = experiment.config()?;
+            let source = experiment.source.source()?;
+            let metrics = rest_main(
+                conf.clone(),
+                source.as_ref(),
+                &logging.out_dir,
+                only_set,
+                logging.log_n,
+                logging.usage_report,
+                logging.coverage_report,
+            )?;
+            print_metrics(&conf, &metrics);
+        }
+        Command::Bench {
+            experiment,
+            logging,
+            rs_values,
+        } => {
+            let mut conf = experiment.config()?;
+            let source = experiment.source.source()?;
+            let mut rest_mode = rest_conf(&conf)?;
+            for rs in rs_values {
+                rest_mode.rs = rs;
+                conf.mode = Mode::Rest(rest_mode);
+                let metrics = rest_main(
+                    conf.clone(),
+                    source.as_ref(),
+                    &logging.out_dir,
+                    false,
+                    logging.log_n,
+                    logging.usage_report,
+                    logging.coverage_report,
+                )?;
+                print_metrics(&conf, &metrics);
+            }
+        }
+        Command::Convert { source, output, to } => {
+            let trajectories = source
+                .source()?
+                .trajectories()?
+                .collect::<Result<Vec<_>, _>>()?;
+            write_trajectories(&trajectories, None, to, CoordinateOrder::default(), &output)?;
+            println!(
+                "{} trajectories written to {}",
+                trajectories.len(),
+                output.display()
+            );
+        }
+        Command::MetricError {
+            source,
+            n,
+            planar_latitude,
+        } => {
+            let trajectories = read_trajectories(source.source()?.as_ref(), 0, n)?;
+            let latitude = planar_latitude
+                .or_else(|| mean_latitude(&trajectories))
+                .ok_or_else(|| Error::Input(String::from("the input has no points")))?;
+            println!("metric,pairs,mean_abs_error_m,max_abs_error_m,max_relative_error");
+            let mut measured = [
+                PointMetric::Haversine,
+                PointMetric::Equirectangular,
+                PointMetric::Planar { latitude },
+            ]
+            .into_iter()
+            .map(|metric| {
+                (
+                    metric.name(),
+                    approximation_error(metric, &trajectories, &trajectories),
+                )
+            })
+            .collect_vec();
+            for kind in [ProjectionKind::Local, ProjectionKind::Utm] {
+                let projected = kind.fit(&trajectories).project_all(trajectories.clone());
+                measured.push((
+                    format!("{} projection", kind.name()),
+                    approximation_error(PointMetric::Projected, &projected, &trajectories),
+                ));
+            }
+            for (name, error) in measured {
+                println!(
+                    "{},{},{:.4},{:.4},{:.2e}",
+                    name, error.pairs, error.mean_abs_m, error.max_abs_m, error.max_relative
+                );
+            }
+        }
+        Command::Sweep {
+            config,
+            source,
+            logging,
+        } => {
+            let configs = load_sweep(config)?;
+            let source = source.source()?;
+            for conf in configs {
+                let metrics = rest_main(
+                    conf.clone(),
+                    source.as_ref(),
+                    &logging.out_dir,
+                    false,
+                    logging.log_n,
+                    logging.usage_report,
+                    logging.coverage_report,
+                )?;
+                print_metrics(&conf, &metrics);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rest::max_dtw;
+    use crate::source::tests::sample_points;
+    use clap::Parser;
+
+    fn run_args(args: &[&str]) -> Result<(), Error> {
+        run(Cli::parse_from(["algo"].iter().chain(args)))
+    }
+
+    #[test]
+    fn test_compress_without_reference_decompresses() {
+        let dir = std::env::temp_dir().join(format!("algo-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let container = dir.join("sample.rstc");
+        let decoded = dir.join("decoded.csv");
+        let (container_arg, decoded_arg) = (container.to_str().unwrap(), decoded.to_str().unwrap());
+        let experiment = ["--input", "sample.csv", "--n", "20", "--rs", "500"];
+
+        let compress = [&["compress", "--output", container_arg][..], &experiment].concat();
+        run_args(&compress).unwrap();
+        let reference = container.with_extension("rstr");
+        let decompress = [
+            "decompress",
+            "--input",
+            container_arg,
+            "--output",
+            decoded_arg,
+        ];
+        // The container references the set compress saved next to it
+        assert!(run_args(&decompress).is_err());
+        let reference_args = ["--reference", reference.to_str().unwrap()];
+        run_args(&[&decompress[..], &reference_args].concat()).unwrap();
+
+        let decompressed = read_trajectories(&CsvSource::new(&decoded), 0, usize::MAX).unwrap();
+        assert_eq!(decompressed.len(), 20);
+        for (original, decompressed) in sample_points().iter().zip(&decompressed) {
+            assert!(max_dtw(original, decompressed, 0, PointMetric::Haversine) < 0.2);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
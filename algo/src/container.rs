@@ -0,0 +1,423 @@
+use crate::rest::{append_raw, EncodedTrajectory, Point, SubTrajectory};
+use crate::temporal::{is_timed, TimeMapping};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+pub const MAGIC: [u8; 4] = *b"RSTC";
+pub const VERSION: u16 = 1;
+
+/// The range of a decoded piece and whether it came from the reference set
+pub type Segment = (Range<usize>, bool);
+
+pub type DecodedSegments = (Vec<Point>, Vec<Segment>);
+
+const RAW_TAG: u8 = 0;
+const REFERENCE_TAG: u8 = 1;
+const TIMED_RAW_TAG: u8 = 2;
+const TIMED_REFERENCE_TAG: u8 = 3;
+
+/// Owned counterpart of `SubTrajectory`. A reference is stored as the id of a
+/// reference trajectory, the inclusive `start..=end` span used from it and the
+/// mapping of its times when the trajectory has times.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StoredSubTrajectory {
+    Trajectory(Vec<Point>),
+    Reference(u32, u32, u32, Option<TimeMapping>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CompressedTrajectory(pub Vec<StoredSubTrajectory>);
+
+impl CompressedTrajectory {
+    /// Resolves the borrowed references of an encoding against the reference set it was encoded with.
+    /// Consecutive raw segments are merged into one, since they share their junction points.
+    /// Returns `None` if a reference slice does not lie inside the set.
+    pub fn from_encoded(
+        encoded: &EncodedTrajectory,
+        reference_set: &[&[Point]],
+    ) -> Option<CompressedTrajectory> {
+        let mut pieces = Vec::new();
+        let mut after_raw = false;
+        for st in &encoded.0 {
+            match (st, pieces.last_mut()) {
+                (SubTrajectory::Trajectory(raw), Some(StoredSubTrajectory::Trajectory(run)))
+                    if after_raw =>
+                {
+                    append_raw(run, raw, true);
+                }
+                (SubTrajectory::Trajectory(raw), _) => {
+                    pieces.push(StoredSubTrajectory::Trajectory(raw.clone()));
+                }
+                (SubTrajectory::Reference(slice, _, mapping), _) => {
+                    let (id, start) = locate(slice, reference_set)?;
+                    pieces.push(StoredSubTrajectory::Reference(
+                        id as u32,
+                        start as u32,
+                        (start + slice.len() - 1) as u32,
+                        *mapping,
+                    ));
+                }
+            }
+            after_raw = matches!(st, SubTrajectory::Trajectory(_));
+        }
+        Some(CompressedTrajectory(pieces))
+    }
+
+    /// Replaces every raw point, such as to take projected points back to lat/lng for storage
+    pub fn map_raw_points(&mut self, f: impl Fn(&Point) -> Point) {
+        for st in self.0.iter_mut() {
+            if let StoredSubTrajectory::Trajectory(raw) = st {
+                for p in raw.iter_mut() {
+                    *p = f(p);
+                }
+            }
+        }
+    }
+
+    /// Reconstructs the polyline, stitching junction points the same way as `rest::decode`.
+    pub fn decode(&self, reference_set: &[&[Point]]) -> io::Result<Vec<Point>> {
+        Ok(self.decode_segments(reference_set)?.0)
+    }
+
+    /// Like `decode`, but also returns the range of the polyline each piece decoded to
+    /// and whether it came from the reference set.
+    pub fn decode_segments(&self, reference_set: &[&[Point]]) -> io::Result<DecodedSegments> {
+        let mut points = Vec::new();
+        let mut segments = Vec::new();
+        let mut after_raw = false;
+        for st in &self.0 {
+            match st {
+                StoredSubTrajectory::Trajectory(raw) => {
+                    let start = append_raw(&mut points, raw, after_raw);
+                    segments.push((start..points.len(), false));
+                    after_raw = true;
+                }
+                &StoredSubTrajectory::Reference(id, start, end, mapping) => {
+                    let span = reference_set
+                        .get(id as usize)
+                        .and_then(|reference| reference.get(start as usize..=end as usize))
+                        .ok_or_else(|| {
+                            invalid_data(format!(
+                                "reference {id}[{start}..={end}] is not in the set"
+                            ))
+                        })?;
+                    segments.push((points.len()..points.len() + span.len(), true));
+                    match mapping {
+                        Some(mapping) => points.extend(mapping.apply(span)),
+                        None => points.extend(span.iter().cloned()),
+                    }
+                    after_raw = false;
+                }
+            }
+        }
+        Ok((points, segments))
+    }
+
+    /// Number of bytes the trajectory occupies in a container, for comparison with `cr_from_shape`
+    pub fn encoded_len(&self) -> usize {
+        4 + self
+            .0
+            .iter()
+            .map(|st| match st {
+                StoredSubTrajectory::Trajectory(raw) if is_timed(raw) => 1 + 4 + raw.len() * 16,
+                StoredSubTrajectory::Trajectory(raw) => 1 + 4 + raw.len() * 8,
+                StoredSubTrajectory::Reference(.., Some(_)) => 1 + 3 * 4 + 2 * 8,
+                StoredSubTrajectory::Reference(..) => 1 + 3 * 4,
+            })
+            .sum::<usize>()
+    }
+}
+
+/// The id of the reference trajectory a slice borrowed from `reference_set` lies in, and
+/// where in it the slice starts
+pub fn locate(slice: &[Point], reference_set: &[impl AsRef<[Point]>]) -> Option<(usize, usize)> {
+    let address = slice.as_ptr() as usize;
+    reference_set
+        .iter()
+        .map(|reference| reference.as_ref())
+        .enumerate()
+        .find_map(|(id, reference)| {
+            let base = reference.as_ptr() as usize;
+            let start = address.checked_sub(base)? / std::mem::size_of::<Point>();
+            (start + slice.len() <= reference.len()).then_some((id, start))
+        })
+}
+
+pub fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Writes a header followed by compressed trajectories.
+///
+/// Layout (little endian): magic `RSTC`, `u16` version, then per trajectory a `u32` piece
+/// count and the pieces. A raw piece is tag `0`, a `u32` point count and `i32` lat/lng pairs.
+/// A reference piece is tag `1` followed by `u32` id, start and end. A raw
+/// piece whose points all have times is tag `2` with the `i64` times after the pairs, and a
+/// reference piece with a time mapping is tag `3` with its `i64` offset and duration after the end.
+pub struct ContainerWriter<W: Write> {
+    writer: W,
+    bytes_written: usize,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<ContainerWriter<W>> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(ContainerWriter {
+            writer,
+            bytes_written: MAGIC.len() + 2,
+        })
+    }
+
+    pub fn write(&mut self, trajectory: &CompressedTrajectory) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(trajectory.encoded_len());
+        buffer.extend((trajectory.0.len() as u32).to_le_bytes());
+        for st in &trajectory.0 {
+            match st {
+                StoredSubTrajectory::Trajectory(raw) if is_timed(raw) => {
+                    buffer.push(TIMED_RAW_TAG);
+                    write_points(&mut buffer, raw);
+                    write_times(&mut buffer, raw);
+                }
+                StoredSubTrajectory::Trajectory(raw) => {
+                    buffer.push(RAW_TAG);
+                    write_points(&mut buffer, raw);
+                }
+                StoredSubTrajectory::Reference(id, start, end, mapping) => {
+                    buffer.push(match mapping {
+                        Some(_) => TIMED_REFERENCE_TAG,
+                        None => REFERENCE_TAG,
+                    });
+                    buffer.extend(id.to_le_bytes());
+                    buffer.extend(start.to_le_bytes());
+                    buffer.extend(end.to_le_bytes());
+                    if let Some(mapping) = mapping {
+                        buffer.extend(mapping.offset.to_le_bytes());
+                        buffer.extend(mapping.duration.to_le_bytes());
+                    }
+                }
+            }
+        }
+        self.writer.write_all(&buffer)?;
+        self.bytes_written += buffer.len();
+        Ok(())
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the trajectories written by a `ContainerWriter`, one per iteration
+pub struct ContainerReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> ContainerReader<R> {
+    pub fn new(mut reader: R) -> io::Result<ContainerReader<R>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data(String::from(
+                "not a compressed trajectory container",
+            )));
+        }
+        let version = read_u16(&mut reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported container version {version}, expected {VERSION}"
+            )));
+        }
+        Ok(ContainerReader { reader })
+    }
+
+    fn read_trajectory(&mut self, pieces: u32) -> io::Result<CompressedTrajectory> {
+        let reader = &mut self.reader;
+        let mut trajectory = CompressedTrajectory::default();
+        for _ in 0..pieces {
+            let mut tag = [0; 1];
+            reader.read_exact(&mut tag)?;
+            trajectory.0.push(match tag[0] {
+                RAW_TAG => StoredSubTrajectory::Trajectory(read_points(reader)?),
+                TIMED_RAW_TAG => {
+                    let mut raw = read_points(reader)?;
+                    read_times(reader, &mut raw)?;
+                    StoredSubTrajectory::Trajectory(raw)
+                }
+                tag @ (REFERENCE_TAG | TIMED_REFERENCE_TAG) => StoredSubTrajectory::Reference(
+                    read_u32(reader)?,
+                    read_u32(reader)?,
+                    read_u32(reader)?,
+                    if tag == TIMED_REFERENCE_TAG {
+                        Some(TimeMapping {
+                            offset: read_i64(reader)?,
+                            duration: read_i64(reader)?,
+                        })
+                    } else {
+                        None
+                    },
+                ),
+                tag => return Err(invalid_data(format!("unknown piece tag {tag}"))),
+            });
+        }
+        Ok(trajectory)
+    }
+}
+
+impl<R: Read> Iterator for ContainerReader<R> {
+    type Item = io::Result<CompressedTrajectory>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A clean end of file between trajectories ends the iteration
+        let mut count = [0; 4];
+        match self.reader.read(&mut count[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        if let Err(e) = self.reader.read_exact(&mut count[1..]) {
+            return Some(Err(e));
+        }
+        Some(self.read_trajectory(u32::from_le_bytes(count)))
+    }
+}
+
+/// Appends a `u32` point count followed by the `i32` lat/lng pairs
+pub fn write_points(buffer: &mut Vec<u8>, points: &[Point]) {
+    buffer.extend((points.len() as u32).to_le_bytes());
+    for point in points {
+        buffer.extend(point.lat.to_le_bytes());
+        buffer.extend(point.lng.to_le_bytes());
+    }
+}
+
+pub fn read_points(reader: &mut impl Read) -> io::Result<Vec<Point>> {
+    let len = read_u32(reader)?;
+    (0..len)
+        .map(|_| {
+            Ok(Point {
+                lat: read_u32(reader)? as i32,
+                lng: read_u32(reader)? as i32,
+                time: None,
+            })
+        })
+        .collect()
+}
+
+/// Appends the `i64` time of every point, which must all have one
+pub fn write_times(buffer: &mut Vec<u8>, points: &[Point]) {
+    for point in points {
+        buffer.extend(point.time.unwrap_or_default().to_le_bytes());
+    }
+}
+
+/// Reads the times `write_times` wrote for `points`
+pub fn read_times(reader: &mut impl Read, points: &mut [Point]) -> io::Result<()> {
+    for point in points {
+        point.time = Some(read_i64(reader)?);
+    }
+    Ok(())
+}
+
+pub fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+pub fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_i64(reader: &mut impl Read) -> io::Result<i64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::MaxDtw;
+    use crate::metric::PointMetric;
+    use crate::rest::{decode, encode};
+    use crate::temporal::sample_times;
+
+    #[test]
+    fn test_container_roundtrip() {
+        let reference = (0..10)
+            .map(|i| Point {
+                lat: 41_150_000 + i * 1000,
+                lng: -8_610_000,
+                time: None,
+            })
+            .collect::<Vec<_>>();
+        let trajectory = (0..12)
+            .map(|i| Point {
+                lat: 41_148_000 + i * 1000,
+                lng: if i < 3 { -8_630_000 } else { -8_610_020 },
+                time: None,
+            })
+            .collect::<Vec<_>>();
+        let mut timed = trajectory.clone();
+        sample_times(&mut timed, 1_372_636_858, 15);
+        let reference_set = [reference.as_slice()];
+        let distance = MaxDtw {
+            band: 0,
+            metric: PointMetric::Haversine,
+        };
+        let (encoded, _) = encode(&reference_set, &trajectory, 200.0, &distance, 0, None, 0.0);
+        let (timed_encoded, _) = encode(&reference_set, &timed, 200.0, &distance, 0, None, 0.0);
+        let compressed = CompressedTrajectory::from_encoded(&encoded, &reference_set).unwrap();
+        assert!(compressed
+            .0
+            .iter()
+            .any(|st| matches!(st, StoredSubTrajectory::Reference(0, _, _, None))));
+        let timed_compressed =
+            CompressedTrajectory::from_encoded(&timed_encoded, &reference_set).unwrap();
+        assert!(timed_compressed
+            .0
+            .iter()
+            .any(|st| matches!(st, StoredSubTrajectory::Reference(0, _, _, Some(_)))));
+
+        let mut writer = ContainerWriter::new(Vec::new()).unwrap();
+        writer.write(&compressed).unwrap();
+        writer.write(&timed_compressed).unwrap();
+        writer.write(&CompressedTrajectory::default()).unwrap();
+        let len = 6 + compressed.encoded_len() + timed_compressed.encoded_len() + 4;
+        assert_eq!(writer.bytes_written(), len);
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len(), len);
+
+        let read = ContainerReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            read,
+            vec![
+                compressed,
+                timed_compressed,
+                CompressedTrajectory::default()
+            ]
+        );
+        assert_eq!(read[0].decode(&reference_set).unwrap(), decode(&encoded).0);
+        // Reference pieces are timed from the source piece they stand in for
+        let decoded = read[1].decode(&reference_set).unwrap();
+        assert_eq!(decoded, decode(&timed_encoded).0);
+        assert_eq!(decoded[0].time, timed[0].time);
+        assert!(decoded.last().unwrap().time <= timed.last().unwrap().time);
+        assert!(decoded.windows(2).all(|w| w[0].time <= w[1].time));
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 9;
+        assert!(ContainerReader::new(wrong_version.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use crate::max_dtw::{dtw, envelope_bound, lower_bound_reaches, Cost, DtwState};
+use crate::metric::PointMetric;
+use crate::rest::Point;
+use crate::temporal::Sed;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// A distance between two trajectories that bounds the error of a compressed piece.
+/// Distances are in km, like `Point::distance`.
+pub trait TrajectoryDistance: Sync {
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64;
+
+    /// A state between growing prefixes of `ta` and `tb`, starting with both empty
+    fn state(&self, ta: &[Point], tb: &[Point]) -> Box<dyn DistanceState>;
+
+    /// The metric between points the distance is built on
+    fn metric(&self) -> PointMetric;
+
+    /// Whether `distance(ta, tb) >= threshold` follows from a bound cheaper than the distance
+    fn lower_bound_reaches(&self, _ta: &[Point], _tb: &[Point], _threshold: f64) -> bool {
+        false
+    }
+}
+
+/// The distance between prefixes of two trajectories, extended one point at a time.
+/// A state keeps no reference to the trajectories, so it can be kept while they are
+/// moved or grown: every push takes them again, and they must be the trajectories the
+/// state was made with, or start with the same points.
+pub trait DistanceState {
+    /// Number of points of `ta` and `tb` pushed so far
+    fn pushed(&self) -> (usize, usize);
+
+    /// Appends the next point of `ta` and returns the new distance
+    fn push_a(&mut self, ta: &[Point], tb: &[Point]) -> f64;
+
+    /// Appends the next point of `tb` and returns the new distance
+    fn push_b(&mut self, ta: &[Point], tb: &[Point]) -> f64;
+
+    fn distance(&self) -> f64;
+
+    /// A lower bound of the distance after pushing more of `ta` but none of `tb`
+    fn push_a_bound(&self) -> f64 {
+        0.0
+    }
+
+    /// A lower bound of the distance after pushing more of either side
+    fn extension_bound(&self) -> f64 {
+        0.0
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DistanceState>;
+}
+
+impl Clone for Box<dyn DistanceState> {
+    fn clone(&self) -> Self {
+        self.boxed_clone()
+    }
+}
+
+impl DistanceState for DtwState {
+    fn pushed(&self) -> (usize, usize) {
+        self.len()
+    }
+
+    fn push_a(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        DtwState::push_a(self, ta, tb)
+    }
+
+    fn push_b(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        DtwState::push_b(self, ta, tb)
+    }
+
+    fn distance(&self) -> f64 {
+        DtwState::distance(self)
+    }
+
+    fn push_a_bound(&self) -> f64 {
+        DtwState::push_a_bound(self)
+    }
+
+    fn extension_bound(&self) -> f64 {
+        self.frontier_min()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DistanceState> {
+        Box::new(self.clone())
+    }
+}
+
+/// Max (bottleneck) DTW, see `max_dtw`
+#[derive(Debug, Clone, Copy)]
+pub struct MaxDtw {
+    pub band: usize,
+    pub metric: PointMetric,
+}
+
+impl TrajectoryDistance for MaxDtw {
+    fn metric(&self) -> PointMetric {
+        self.metric
+    }
+
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64 {
+        dtw(ta, tb, self.band, Cost::Max, self.metric)
+    }
+
+    fn state(&self, _ta: &[Point], _tb: &[Point]) -> Box<dyn DistanceState> {
+        Box::new(DtwState::new(self.band, Cost::Max, self.metric))
+    }
+
+    fn lower_bound_reaches(&self, ta: &[Point], tb: &[Point], threshold: f64) -> bool {
+        lower_bound_reaches(ta, tb, self.band, self.metric, threshold)
+    }
+}
+
+/// Classic DTW, the sum of the distances along the best warping path
+#[derive(Debug, Clone, Copy)]
+pub struct SumDtw {
+    pub band: usize,
+    pub metric: PointMetric,
+}
+
+impl TrajectoryDistance for SumDtw {
+    fn metric(&self) -> PointMetric {
+        self.metric
+    }
+
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64 {
+        dtw(ta, tb, self.band, Cost::Sum, self.metric)
+    }
+
+    fn state(&self, _ta: &[Point], _tb: &[Point]) -> Box<dyn DistanceState> {
+        Box::new(DtwState::new(self.band, Cost::Sum, self.metric))
+    }
+
+    /// The sum along a path is at least its largest distance, so the max-DTW bounds hold
+    fn lower_bound_reaches(&self, ta: &[Point], tb: &[Point], threshold: f64) -> bool {
+        lower_bound_reaches(ta, tb, self.band, self.metric, threshold)
+    }
+}
+
+/// Discrete Fréchet distance (Eiter and Mannila). Its coupling recurrence is the one of
+/// max-DTW, so this is max-DTW without a band.
+#[derive(Debug, Clone, Copy)]
+pub struct DiscreteFrechet {
+    pub metric: PointMetric,
+}
+
+impl DiscreteFrechet {
+    fn max_dtw(&self) -> MaxDtw {
+        MaxDtw {
+            band: 0,
+            metric: self.metric,
+        }
+    }
+}
+
+impl TrajectoryDistance for DiscreteFrechet {
+    fn metric(&self) -> PointMetric {
+        self.metric
+    }
+
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64 {
+        self.max_dtw().distance(ta, tb)
+    }
+
+    fn state(&self, ta: &[Point], tb: &[Point]) -> Box<dyn DistanceState> {
+        self.max_dtw().state(ta, tb)
+    }
+
+    fn lower_bound_reaches(&self, ta: &[Point], tb: &[Point], threshold: f64) -> bool {
+        self.max_dtw().lower_bound_reaches(ta, tb, threshold)
+    }
+}
+
+/// Hausdorff distance: the largest distance from a point of either side to the nearest
+/// point of the other side. Ignores the order of the points.
+#[derive(Debug, Clone, Copy)]
+pub struct Hausdorff {
+    pub metric: PointMetric,
+}
+
+impl TrajectoryDistance for Hausdorff {
+    fn metric(&self) -> PointMetric {
+        self.metric
+    }
+
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64 {
+        let mut state = HausdorffState::new(self.metric);
+        for _ in ta {
+            state.push_a(ta, tb);
+        }
+        for _ in tb {
+            state.push_b(ta, tb);
+        }
+        state.distance()
+    }
+
+    fn state(&self, _ta: &[Point], _tb: &[Point]) -> Box<dyn DistanceState> {
+        Box::new(HausdorffState::new(self.metric))
+    }
+
+    /// Every point is at least as far from the other side as from its bounding box
+    fn lower_bound_reaches(&self, ta: &[Point], tb: &[Point], threshold: f64) -> bool {
+        envelope_bound(ta, tb, 0, self.metric) >= threshold
+    }
+}
+
+/// Keeps the distance from every point pushed to the nearest point pushed on the other
+/// side. Pushing a point may bring the other side closer, so there are no extension bounds.
+#[derive(Clone)]
+struct HausdorffState {
+    metric: PointMetric,
+    nearest_a: Vec<f64>,
+    nearest_b: Vec<f64>,
+}
+
+impl HausdorffState {
+    fn new(metric: PointMetric) -> HausdorffState {
+        HausdorffState {
+            metric,
+            nearest_a: Vec::new(),
+            nearest_b: Vec::new(),
+        }
+    }
+}
+
+impl DistanceState for HausdorffState {
+    fn pushed(&self) -> (usize, usize) {
+        (self.nearest_a.len(), self.nearest_b.len())
+    }
+
+    fn push_a(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let a = &ta[self.nearest_a.len()];
+        let mut nearest = f64::MAX;
+        for (b, nearest_b) in tb.iter().zip(self.nearest_b.iter_mut()) {
+            let distance = self.metric.distance(a, b);
+            nearest = nearest.min(distance);
+            *nearest_b = nearest_b.min(distance);
+        }
+        self.nearest_a.push(nearest);
+        self.distance()
+    }
+
+    fn push_b(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let b = &tb[self.nearest_b.len()];
+        let mut nearest = f64::MAX;
+        for (a, nearest_a) in ta.iter().zip(self.nearest_a.iter_mut()) {
+            let distance = self.metric.distance(a, b);
+            nearest = nearest.min(distance);
+            *nearest_a = nearest_a.min(distance);
+        }
+        self.nearest_b.push(nearest);
+        self.distance()
+    }
+
+    fn distance(&self) -> f64 {
+        if self.nearest_a.is_empty() && self.nearest_b.is_empty() {
+            return 0.0;
+        }
+        self.nearest_a
+            .iter()
+            .chain(&self.nearest_b)
+            .copied()
+            .fold(0.0, f64::max)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DistanceState> {
+        Box::new(self.clone())
+    }
+}
+
+/// The distance a run bounds its error by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceKind {
+    #[default]
+    MaxDtw,
+    SumDtw,
+    Frechet,
+    Hausdorff,
+    /// Synchronized Euclidean distance, which takes the times of the points into account
+    Sed,
+}
+
+impl DistanceKind {
+    /// The distance between points under `metric`, with `band` applying to the DTW distances
+    pub fn measure(self, band: usize, metric: PointMetric) -> Box<dyn TrajectoryDistance> {
+        match self {
+            DistanceKind::MaxDtw => Box::new(MaxDtw { band, metric }),
+            DistanceKind::SumDtw => Box::new(SumDtw { band, metric }),
+            DistanceKind::Frechet => Box::new(DiscreteFrechet { metric }),
+            DistanceKind::Hausdorff => Box::new(Hausdorff { metric }),
+            DistanceKind::Sed => Box::new(Sed { metric }),
+        }
+    }
+
+    /// Tag stored in reference set files
+    pub fn code(self) -> u8 {
+        match self {
+            DistanceKind::MaxDtw => 0,
+            DistanceKind::SumDtw => 1,
+            DistanceKind::Frechet => 2,
+            DistanceKind::Hausdorff => 3,
+            DistanceKind::Sed => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<DistanceKind> {
+        match code {
+            0 => Some(DistanceKind::MaxDtw),
+            1 => Some(DistanceKind::SumDtw),
+            2 => Some(DistanceKind::Frechet),
+            3 => Some(DistanceKind::Hausdorff),
+            4 => Some(DistanceKind::Sed),
+            _ => None,
+        }
+    }
+
+    /// Name as written in sweep files
+    pub fn name(self) -> &'static str {
+        match self {
+            DistanceKind::MaxDtw => "max_dtw",
+            DistanceKind::SumDtw => "sum_dtw",
+            DistanceKind::Frechet => "frechet",
+            DistanceKind::Hausdorff => "hausdorff",
+            DistanceKind::Sed => "sed",
+        }
+    }
+
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            DistanceKind::MaxDtw => "",
+            DistanceKind::SumDtw => "-SUMDTW",
+            DistanceKind::Frechet => "-FRECHET",
+            DistanceKind::Hausdorff => "-HAUSDORFF",
+            DistanceKind::Sed => "-SED",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distances_on_shifted_line() {
+        let line = (0..6)
+            .map(|i| Point {
+                lat: 41_150_000 + i * 1000,
+                lng: -8_610_000,
+                time: None,
+            })
+            .collect::<Vec<_>>();
+        // The same line moved one point along itself, and moved sideways
+        let shifted = line[1..].to_vec();
+        let beside = line
+            .iter()
+            .map(|p| Point {
+                lat: p.lat,
+                lng: p.lng + 1000,
+                time: None,
+            })
+            .collect::<Vec<_>>();
+        let step = line[0].distance(&line[1]);
+        let side = line[0].distance(&beside[0]);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        let metric = PointMetric::Haversine;
+        let hausdorff = Hausdorff { metric };
+
+        assert_eq!(MaxDtw { band: 0, metric }.distance(&line, &shifted), step);
+        assert_eq!(DiscreteFrechet { metric }.distance(&line, &shifted), step);
+        // Only the first point has no partner at the same position
+        assert_eq!(hausdorff.distance(&line, &shifted), step);
+        assert!(close(
+            SumDtw { band: 0, metric }.distance(&line, &shifted),
+            step
+        ));
+        assert!(close(hausdorff.distance(&line, &line[..3]), 3.0 * step));
+
+        assert!(close(
+            MaxDtw { band: 1, metric }.distance(&line, &beside),
+            side
+        ));
+        let paired = line.iter().zip(&beside).map(|(a, b)| a.distance(b)).sum();
+        assert!(close(
+            SumDtw { band: 1, metric }.distance(&line, &beside),
+            paired
+        ));
+
+        let mut state = hausdorff.state(&line, &shifted);
+        state.push_a(&line, &shifted);
+        assert_eq!(state.push_b(&line, &shifted), step);
+        assert_eq!(state.pushed(), (1, 1));
+        for _ in 1..line.len() {
+            state.push_a(&line, &shifted);
+        }
+        for _ in 1..shifted.len() {
+            state.push_b(&line, &shifted);
+        }
+        assert_eq!(state.distance(), hausdorff.distance(&line, &shifted));
+    }
+}
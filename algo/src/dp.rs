@@ -1,41 +1,107 @@
-use crate::max_dtw::{max_dtw, max_dtw_band};
+use crate::distance::TrajectoryDistance;
+use crate::metric::PointMetric;
+use crate::online::{simplify_online, DeadReckoning, OpeningWindow, SquishE, SQUISH_CAPACITY};
 use crate::rest::Point;
-use append_only_vec::AppendOnlyVec;
+use crate::temporal::relative_times;
 use itertools::Itertools;
-use std::collections::HashMap;
+use serde::Deserialize;
 
-fn dtw<'a>(
-    ta: &'a [Point],
-    tb: &'a [Point],
-    map: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
-    band: usize,
-) -> f64 {
-    if band != 0 {
-        max_dtw_band(ta, tb, map, band)
-    } else {
-        max_dtw(ta, tb, map, None)
+/// The line simplification a DP mode run compresses with. `epsilon` is in km for all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Simplifier {
+    /// Douglas-Peucker until the run's trajectory distance to the original is within epsilon
+    #[default]
+    Dp,
+    /// Douglas-Peucker until every point is within epsilon of its simplified segment
+    DpPoint,
+    /// Top-down time-ratio: Douglas-Peucker on the synchronized Euclidean distance
+    TdTr,
+    /// Visvalingam-Whyatt
+    Visvalingam,
+    /// Online: Opening Window, see `online`
+    OpeningWindow,
+    /// Online: SQUISH-E with a buffer of `SQUISH_CAPACITY` points
+    SquishE,
+    /// Online: Dead Reckoning
+    DeadReckoning,
+}
+
+impl Simplifier {
+    pub fn simplify(
+        self,
+        polyline: &[Point],
+        epsilon: f64,
+        distance: &dyn TrajectoryDistance,
+    ) -> Vec<Point> {
+        let metric = distance.metric();
+        match self {
+            Simplifier::Dp => douglas_peucker(polyline, epsilon, distance),
+            Simplifier::DpPoint => split_while(polyline, epsilon, |k, i, j| {
+                perpendicular_distance(&polyline[k], &polyline[i], &polyline[j], metric)
+            }),
+            Simplifier::TdTr => {
+                let times = relative_times(polyline);
+                split_while(polyline, epsilon, |k, i, j| {
+                    let span = times[j] - times[i];
+                    let ratio = if span > 0.0 {
+                        (times[k] - times[i]) / span
+                    } else {
+                        0.0
+                    };
+                    synchronized_distance(&polyline[k], &polyline[i], &polyline[j], ratio, metric)
+                })
+            }
+            Simplifier::Visvalingam => visvalingam_whyatt(polyline, epsilon, metric),
+            Simplifier::OpeningWindow => {
+                simplify_online(&mut OpeningWindow::new(epsilon, metric), polyline)
+            }
+            Simplifier::SquishE => simplify_online(
+                &mut SquishE::new(epsilon, metric, SQUISH_CAPACITY),
+                polyline,
+            ),
+            Simplifier::DeadReckoning => {
+                simplify_online(&mut DeadReckoning::new(epsilon, metric), polyline)
+            }
+        }
+    }
+
+    /// Name of the mode in the logs
+    pub fn mode_name(self) -> &'static str {
+        match self {
+            Simplifier::Dp => "DP",
+            Simplifier::DpPoint => "DP_PT",
+            Simplifier::TdTr => "TDTR",
+            Simplifier::Visvalingam => "VW",
+            Simplifier::OpeningWindow => "OPW",
+            Simplifier::SquishE => "SQUISHE",
+            Simplifier::DeadReckoning => "DR",
+        }
+    }
+
+    /// Whether the simplifier stops on the run's trajectory distance, and so on its DTW band
+    pub fn uses_distance(self) -> bool {
+        self == Simplifier::Dp
     }
 }
-pub fn douglas_peucker(polyline: &[Point], epsilon: f64, band: usize) -> Vec<Point> {
+
+pub fn douglas_peucker(
+    polyline: &[Point],
+    epsilon: f64,
+    distance: &dyn TrajectoryDistance,
+) -> Vec<Point> {
     let mut indices: Vec<usize> = vec![0, polyline.len() - 1];
-    let dp_vecs: AppendOnlyVec<Vec<Point>> = AppendOnlyVec::new();
-    dp_vecs.push(indices.iter().map(|&i| polyline[i].clone()).collect_vec());
-    let mut hash_map = std::collections::HashMap::new();
-
-    while dtw(
-        &dp_vecs[dp_vecs.len() - 1].as_slice(),
-        polyline,
-        &mut hash_map,
-        band,
-    ) > epsilon
-    {
+    let mut simplified = indices.iter().map(|&i| polyline[i].clone()).collect_vec();
+
+    while distance.distance(&simplified, polyline) > epsilon {
         let mut max_dist = (f64::MIN, 0);
-        (0..indices.len() - 1).into_iter().for_each(|i| {
-            (indices[i] + 1..indices[i + 1]).into_iter().for_each(|j| {
+        (0..indices.len() - 1).for_each(|i| {
+            (indices[i] + 1..indices[i + 1]).for_each(|j| {
                 let dist = perpendicular_distance(
                     &polyline[j],
                     &polyline[indices[i]],
                     &polyline[indices[i + 1]],
+                    distance.metric(),
                 );
                 if dist > max_dist.0 {
                     max_dist = (dist, j);
@@ -44,55 +110,143 @@ pub fn douglas_peucker(polyline: &[Point], epsilon: f64, band: usize) -> Vec<Poi
         });
         indices.push(max_dist.1);
         indices.sort();
-        dp_vecs.push(indices.iter().map(|&i| polyline[i].clone()).collect_vec());
+        simplified = indices.iter().map(|&i| polyline[i].clone()).collect_vec();
+    }
+    simplified
+}
+
+/// Distance from `p` to the line through `p1` and `p2`, in the plane `metric` measures in
+pub fn perpendicular_distance(p: &Point, p1: &Point, p2: &Point, metric: PointMetric) -> f64 {
+    let (dx, dy) = metric.offset(p1, p2);
+    let (x, y) = metric.offset(p1, p);
+    let length = dx.powi(2) + dy.powi(2);
+    if length == 0.0 {
+        return x.hypot(y);
+    }
+    // The foot of the perpendicular is k along the line from p1 to p2
+    let k = (x * dx + y * dy) / length;
+    (x - k * dx).hypot(y - k * dy)
+}
+
+/// Top-down simplification: keeps the endpoints, then splits every segment at its most
+/// deviating point while that deviation exceeds `epsilon`. `deviation(k, i, j)` is how far
+/// point `k` is from the segment from point `i` to point `j`.
+fn split_while(
+    polyline: &[Point],
+    epsilon: f64,
+    deviation: impl Fn(usize, usize, usize) -> f64,
+) -> Vec<Point> {
+    if polyline.len() < 3 {
+        return polyline.to_vec();
     }
-    dp_vecs[dp_vecs.len() - 1].clone()
+    let mut keep = vec![false; polyline.len()];
+    keep[0] = true;
+    keep[polyline.len() - 1] = true;
+    let mut segments = vec![(0, polyline.len() - 1)];
+    while let Some((i, j)) = segments.pop() {
+        let furthest = (i + 1..j)
+            .map(|k| (deviation(k, i, j), k))
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some((dist, k)) = furthest {
+            if dist > epsilon {
+                keep[k] = true;
+                segments.extend([(i, k), (k, j)]);
+            }
+        }
+    }
+    polyline
+        .iter()
+        .zip(keep)
+        .filter(|&(_, keep)| keep)
+        .map(|(p, _)| p.clone())
+        .collect()
+}
+
+/// Distance from `p` to where an object moving uniformly from `p1` to `p2` is at the
+/// time `p` was sampled, `ratio` of the way from the time of `p1` to the time of `p2`
+pub fn synchronized_distance(
+    p: &Point,
+    p1: &Point,
+    p2: &Point,
+    ratio: f64,
+    metric: PointMetric,
+) -> f64 {
+    let (dx, dy) = metric.offset(p1, p2);
+    let (x, y) = metric.offset(p1, p);
+    (x - ratio * dx).hypot(y - ratio * dy)
 }
 
-fn perpendicular_distance(p: &Point, p1: &Point, p2: &Point) -> f64 {
-    // Constants
-    let earth_radius = 6371000.0; // in meters
-
-    // Convert degrees to radians
-    let lat1 = p1.lat_as_f32().to_radians();
-    let lng1 = p1.lng_as_f32().to_radians();
-    let lat2 = p2.lat_as_f32().to_radians();
-    let lng2 = p2.lng_as_f32().to_radians();
-    let latc = p.lat_as_f32().to_radians();
-    let lngc = p.lng_as_f32().to_radians();
-
-    // Reference latitude for projection
-    let lat_ref = (lat1 + lat2) / 2.0;
-
-    // Convert geographic coordinates to Cartesian coordinates
-    let x1 = earth_radius * (lng1 - lng1) * lat_ref.cos();
-    let y1 = earth_radius * (lat1 - lat_ref);
-    let x2 = earth_radius * (lng2 - lng1) * lat_ref.cos();
-    let y2 = earth_radius * (lat2 - lat_ref);
-    let xc = earth_radius * (lngc - lng1) * lat_ref.cos();
-    let yc = earth_radius * (latc - lat_ref);
-
-    // Compute the direction vector for line AB
-    let dx = x2 - x1;
-    let dy = y2 - y1;
-
-    // Find intersection point D using projection formula
-    let k = ((yc - y1) * dy + (xc - x1) * dx) / (dx.powi(2) + dy.powi(2));
-    let xd = x1 + k * dx;
-    let yd = y1 + k * dy;
-
-    // Convert Cartesian back to geographic coordinates
-    let lat_d = yd / earth_radius + lat_ref;
-    let lng_d = xd / (earth_radius * lat_ref.cos()) + lng1;
-
-    p.distance(&Point::from((lat_d.to_degrees(), lng_d.to_degrees())))
+/// Area in km² of the triangle of three points, in the plane `metric` measures in
+fn triangle_area(a: &Point, b: &Point, c: &Point, metric: PointMetric) -> f64 {
+    let (x1, y1) = metric.offset(a, b);
+    let (x2, y2) = metric.offset(a, c);
+    (x1 * y2 - x2 * y1).abs() / 2.0
+}
+
+/// Visvalingam-Whyatt: repeatedly drops the point whose triangle with its neighbours has
+/// the least area, while that area is below the one of a triangle whose base and height
+/// are both `epsilon`. The area of a point never drops below the area of a point dropped
+/// before it, so points are dropped in the order their effective area ranks them.
+pub fn visvalingam_whyatt(polyline: &[Point], epsilon: f64, metric: PointMetric) -> Vec<Point> {
+    let n = polyline.len();
+    if n < 3 {
+        return polyline.to_vec();
+    }
+    let threshold = epsilon * epsilon / 2.0;
+    // Neighbours of every point among the ones kept so far
+    let mut previous = (0..n).map(|i| i.wrapping_sub(1)).collect_vec();
+    let mut next = (1..=n).collect_vec();
+    let mut area = vec![f64::INFINITY; n];
+    for i in 1..n - 1 {
+        area[i] = triangle_area(&polyline[i - 1], &polyline[i], &polyline[i + 1], metric);
+    }
+    let mut kept = n;
+    let mut last_area: f64 = 0.0;
+    while kept > 2 {
+        let (i, &smallest) = area
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .expect("the polyline has interior points");
+        let effective = smallest.max(last_area);
+        if effective >= threshold {
+            break;
+        }
+        last_area = effective;
+        let (before, after) = (previous[i], next[i]);
+        next[before] = after;
+        previous[after] = before;
+        area[i] = f64::INFINITY;
+        kept -= 1;
+        for j in [before, after] {
+            if j != 0 && j != n - 1 {
+                area[j] = triangle_area(
+                    &polyline[previous[j]],
+                    &polyline[j],
+                    &polyline[next[j]],
+                    metric,
+                );
+            }
+        }
+    }
+    let mut simplified = Vec::with_capacity(kept);
+    let mut i = 0;
+    while i < n {
+        simplified.push(polyline[i].clone());
+        i = next[i];
+    }
+    simplified
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::distance::MaxDtw;
+    use crate::source::tests::longest_timed_trip;
+    use crate::temporal::sed;
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn test_douglas_peucker_simple() {
         // Define a simple polyline (a square shape)
         let points = vec![
@@ -107,7 +261,14 @@ mod tests {
         // Epsilon is chosen such that the simplified polyline should ideally remove the middle points
         let epsilon = 0.2; // Adjust this based on the scale of your Point::distance implementation
 
-        let simplified_polyline = douglas_peucker(&points, epsilon, 2);
+        let simplified_polyline = douglas_peucker(
+            &points,
+            epsilon,
+            &MaxDtw {
+                band: 2,
+                metric: PointMetric::Haversine,
+            },
+        );
 
         // Ensure the simplified polyline has fewer points
         assert!(simplified_polyline.len() < points.len());
@@ -133,4 +294,45 @@ mod tests {
         assert_eq!(simplified_polyline[0], points[0]);
         assert_eq!(simplified_polyline.last(), points.last());
     }
+
+    #[test]
+    fn test_simplifiers_bound_their_deviation() {
+        let mut points = longest_timed_trip();
+        // Linger at one point for a minute, which only the time-aware simplifier sees
+        for p in points[10..].iter_mut() {
+            p.time = p.time.map(|t| t + 60);
+        }
+        let metric = PointMetric::Haversine;
+        let distance = MaxDtw { band: 0, metric };
+        let epsilon = 0.2;
+
+        for simplifier in [
+            Simplifier::Dp,
+            Simplifier::DpPoint,
+            Simplifier::TdTr,
+            Simplifier::Visvalingam,
+            Simplifier::OpeningWindow,
+            Simplifier::SquishE,
+            Simplifier::DeadReckoning,
+        ] {
+            let simplified = simplifier.simplify(&points, epsilon, &distance);
+            assert!(simplified.len() < points.len(), "{simplifier:?}");
+            assert_eq!(simplified[0], points[0]);
+            assert_eq!(simplified.last(), points.last());
+        }
+        let dp_point = Simplifier::DpPoint.simplify(&points, epsilon, &distance);
+        let td_tr = Simplifier::TdTr.simplify(&points, epsilon, &distance);
+        assert!(sed(&points, &td_tr, metric) <= epsilon);
+        assert!(sed(&points, &dp_point, metric) > epsilon);
+        // Every dropped point is within epsilon of the segment it was dropped from
+        let mut kept = dp_point.iter().peekable();
+        let mut segment = (kept.next().unwrap(), kept.peek().cloned());
+        for p in &points {
+            if Some(p) == segment.1 {
+                segment = (kept.next().unwrap(), kept.peek().cloned());
+            } else if let Some(end) = segment.1 {
+                assert!(perpendicular_distance(p, segment.0, end, metric) <= epsilon);
+            }
+        }
+    }
 }
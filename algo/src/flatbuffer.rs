@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::rest::Point;
+use crate::source::{SourceTrajectory, TrajectoryIter, TrajectorySource};
+use flatbuffers::{FlatBufferBuilder, VerifierOptions};
+use std::io::Write;
+use std::path::Path;
+
+#[allow(warnings, clippy::all)]
+#[rustfmt::skip]
+#[path = "../generated/trajectory_generated.rs"]
+mod trajectory_generated;
+
+use trajectory_generated::trajectory as fb;
+
+/// The default verifier limits are far below the number of trajectories in our datasets
+fn verifier_options() -> VerifierOptions {
+    VerifierOptions {
+        max_tables: usize::MAX,
+        max_apparent_size: usize::MAX,
+        ..VerifierOptions::default()
+    }
+}
+
+/// The trajectories of a `Trajectories` root, or of a single `Trajectory` root like
+/// `generated/trajectory_sample.bin`
+fn verified(bytes: &[u8]) -> Result<Vec<fb::Trajectory<'_>>, Error> {
+    match fb::root_as_trajectories_with_opts(&verifier_options(), bytes) {
+        Ok(root) => Ok(root
+            .trajectories()
+            .map(|trajectories| trajectories.iter().collect())
+            .unwrap_or_default()),
+        Err(e) => flatbuffers::root_with_opts::<fb::Trajectory>(&verifier_options(), bytes)
+            .map(|trajectory| vec![trajectory])
+            .map_err(|_| Error::Input(format!("invalid trajectory flatbuffer: {e}"))),
+    }
+}
+
+/// A `Trajectory.Trajectories` flatbuffer (see `trajectory.fbs`), or a single
+/// `Trajectory.Trajectory`, held in memory.
+/// Trajectories are read in place, without parsing.
+pub struct FlatBufferSource {
+    bytes: Vec<u8>,
+}
+
+impl FlatBufferSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<FlatBufferSource, Error> {
+        FlatBufferSource::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<FlatBufferSource, Error> {
+        verified(&bytes)?;
+        Ok(FlatBufferSource { bytes })
+    }
+}
+
+impl TrajectorySource for FlatBufferSource {
+    fn trajectories(&self) -> Result<TrajectoryIter<'_>, Error> {
+        let trajectories = verified(&self.bytes)?;
+        Ok(Box::new(trajectories.into_iter().map(|t| {
+            Ok(SourceTrajectory {
+                id: t.id().map(String::from),
+                timestamp: None,
+                points: t
+                    .polyline()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|p| Point::from((p.lat(), p.lng())))
+                    .collect(),
+            })
+        })))
+    }
+}
+
+/// Writes the trajectories into a single flatbuffer and returns how many were written.
+/// The schema has no times, so trajectories with any are rejected rather than losing them.
+pub fn write_flatbuffer(
+    trajectories: impl IntoIterator<Item = Result<SourceTrajectory, Error>>,
+    mut writer: impl Write,
+) -> Result<usize, Error> {
+    let mut builder = FlatBufferBuilder::new();
+    let mut offsets = Vec::new();
+    for trajectory in trajectories {
+        let trajectory = trajectory?;
+        if trajectory.points.iter().any(|p| p.time.is_some()) {
+            return Err(Error::Input(format!(
+                "trajectory {} has times, which flatbuffers do not store",
+                trajectory
+                    .id
+                    .as_deref()
+                    .unwrap_or(&(offsets.len() + 1).to_string())
+            )));
+        }
+        let id = trajectory.id.as_deref().map(|id| builder.create_string(id));
+        let points = trajectory
+            .points
+            .iter()
+            .map(|p| fb::Point::new(p.lat_as_f32(), p.lng_as_f32()))
+            .collect::<Vec<_>>();
+        let polyline = builder.create_vector(&points);
+        offsets.push(fb::Trajectory::create(
+            &mut builder,
+            &fb::TrajectoryArgs {
+                id,
+                polyline: Some(polyline),
+            },
+        ));
+    }
+    let trajectories = builder.create_vector(&offsets);
+    let root = fb::Trajectories::create(
+        &mut builder,
+        &fb::TrajectoriesArgs {
+            trajectories: Some(trajectories),
+        },
+    );
+    fb::finish_trajectories_buffer(&mut builder, root);
+    writer.write_all(builder.finished_data())?;
+    writer.flush()?;
+    Ok(offsets.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CsvSource;
+
+    #[test]
+    fn test_flatbuffer_roundtrip() {
+        let csv = CsvSource::new("sample.csv");
+        let mut bytes = Vec::new();
+        assert_eq!(
+            write_flatbuffer(csv.trajectories().unwrap(), &mut bytes).unwrap(),
+            29
+        );
+
+        let expected = csv
+            .trajectories()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let read = FlatBufferSource::from_bytes(bytes)
+            .unwrap()
+            .trajectories()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read.len(), expected.len());
+        for (a, b) in read.iter().zip(&expected) {
+            assert_eq!(a.points.len(), b.points.len());
+            for (p, q) in a.points.iter().zip(&b.points) {
+                // Stored as f32 degrees, so allow one microdegree of rounding
+                assert!((p.lat - q.lat).abs() <= 1 && (p.lng - q.lng).abs() <= 1);
+            }
+        }
+        assert!(FlatBufferSource::from_bytes(vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_flatbuffer_rejects_times() {
+        let mut timed = CsvSource::new("sample.csv")
+            .trajectories()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        timed.points[1].time = Some(15);
+        let mut bytes = Vec::new();
+        assert!(matches!(
+            write_flatbuffer([Ok(timed)], &mut bytes),
+            Err(Error::Input(_))
+        ));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_flatbuffer_reads_single_trajectory_fixture() {
+        let read = FlatBufferSource::open("generated/trajectory_sample.bin")
+            .unwrap()
+            .trajectories()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].id.as_deref(), Some("1"));
+        assert_eq!(read[0].points.len(), 4);
+        assert_eq!(read[0].points[0], Point::from((13.41, 75.13)));
+    }
+}
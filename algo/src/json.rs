@@ -0,0 +1,263 @@
+use crate::error::Error;
+use crate::rest::Point;
+use crate::source::{CoordinateOrder, SourceTrajectory, TrajectoryIter, TrajectorySource};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// A position in the `{lat, lng}` object form of `trajectory_sample.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct JsonPoint {
+    pub lat: f32,
+    pub lng: f32,
+    /// Seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonTrajectory {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub polyline: Vec<JsonPoint>,
+}
+
+/// A file holds either a single trajectory object or an array of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonTrajectories {
+    One(JsonTrajectory),
+    Many(Vec<JsonTrajectory>),
+}
+
+impl From<&SourceTrajectory> for JsonTrajectory {
+    fn from(trajectory: &SourceTrajectory) -> JsonTrajectory {
+        JsonTrajectory {
+            id: trajectory.id.clone(),
+            polyline: trajectory
+                .points
+                .iter()
+                .map(|p| JsonPoint {
+                    lat: p.lat_as_f32(),
+                    lng: p.lng_as_f32(),
+                    time: p.time,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<JsonTrajectory> for SourceTrajectory {
+    type Error = Error;
+
+    fn try_from(trajectory: JsonTrajectory) -> Result<SourceTrajectory, Error> {
+        Ok(SourceTrajectory {
+            id: trajectory.id,
+            timestamp: trajectory.polyline.first().and_then(|p| p.time),
+            points: trajectory
+                .polyline
+                .iter()
+                .map(|p| {
+                    Ok(Point {
+                        time: p.time,
+                        ..CoordinateOrder::LatLng.point(&[p.lat, p.lng])?
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+}
+
+/// GeoJSON objects needed to hold trajectories. Positions are `[lng, lat]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum GeoJson {
+    FeatureCollection {
+        features: Vec<GeoJson>,
+    },
+    Feature {
+        geometry: Box<GeoJson>,
+        #[serde(default)]
+        properties: Map<String, Value>,
+    },
+    LineString {
+        coordinates: Vec<Vec<f32>>,
+    },
+}
+
+impl GeoJson {
+    pub fn line_string(points: &[Point]) -> GeoJson {
+        GeoJson::LineString {
+            coordinates: points
+                .iter()
+                .map(|p| CoordinateOrder::GeoJson.position(p).to_vec())
+                .collect(),
+        }
+    }
+
+    pub fn feature(points: &[Point], properties: Map<String, Value>) -> GeoJson {
+        GeoJson::Feature {
+            geometry: Box::new(GeoJson::line_string(points)),
+            properties,
+        }
+    }
+
+    /// Flattens the object into its line strings, taking ids from the `id` property of features
+    pub fn trajectories(self) -> Result<Vec<SourceTrajectory>, Error> {
+        match self {
+            GeoJson::FeatureCollection { features } => Ok(features
+                .into_iter()
+                .map(GeoJson::trajectories)
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .flatten()
+                .collect()),
+            GeoJson::Feature {
+                geometry,
+                properties,
+            } => {
+                let id = properties.get("id").map(|id| match id {
+                    Value::String(id) => id.clone(),
+                    id => id.to_string(),
+                });
+                let mut trajectories = geometry.trajectories()?;
+                for trajectory in trajectories.iter_mut() {
+                    trajectory.id.clone_from(&id);
+                }
+                Ok(trajectories)
+            }
+            GeoJson::LineString { coordinates } => Ok(vec![SourceTrajectory {
+                id: None,
+                timestamp: None,
+                points: coordinates
+                    .iter()
+                    .map(|position| CoordinateOrder::GeoJson.point(position))
+                    .collect::<Result<_, _>>()?,
+            }]),
+        }
+    }
+}
+
+fn id_properties(id: Option<&str>) -> Map<String, Value> {
+    let mut properties = Map::new();
+    if let Some(id) = id {
+        properties.insert(String::from("id"), Value::from(id));
+    }
+    properties
+}
+
+pub fn trajectory_feature(id: Option<&str>, points: &[Point]) -> GeoJson {
+    GeoJson::feature(points, id_properties(id))
+}
+
+/// One feature per decoded segment, tagged with `kind` `raw` or `reference`
+pub fn segment_features(
+    id: Option<&str>,
+    points: &[Point],
+    segments: impl IntoIterator<Item = (Range<usize>, bool)>,
+) -> Vec<GeoJson> {
+    segments
+        .into_iter()
+        .map(|(range, is_reference)| {
+            let mut properties = id_properties(id);
+            properties.insert(
+                String::from("kind"),
+                Value::from(if is_reference { "reference" } else { "raw" }),
+            );
+            GeoJson::feature(&points[range], properties)
+        })
+        .collect()
+}
+
+/// Reads a JSON trajectory file, or a GeoJSON file when `geojson` is set
+#[derive(Debug, Clone)]
+pub struct JsonSource {
+    pub path: PathBuf,
+    pub geojson: bool,
+}
+
+impl TrajectorySource for JsonSource {
+    fn trajectories(&self) -> Result<TrajectoryIter<'_>, Error> {
+        let text = std::fs::read_to_string(&self.path)?;
+        let trajectories = if self.geojson {
+            serde_json::from_str::<GeoJson>(&text)?.trajectories()?
+        } else {
+            match serde_json::from_str(&text)? {
+                JsonTrajectories::One(trajectory) => vec![trajectory.try_into()?],
+                JsonTrajectories::Many(trajectories) => trajectories
+                    .into_iter()
+                    .map(SourceTrajectory::try_from)
+                    .collect::<Result<_, _>>()?,
+            }
+        };
+        Ok(Box::new(trajectories.into_iter().map(Ok)))
+    }
+}
+
+pub fn write_json(trajectories: &[SourceTrajectory], mut writer: impl Write) -> Result<(), Error> {
+    serde_json::to_writer(
+        &mut writer,
+        &trajectories
+            .iter()
+            .map(JsonTrajectory::from)
+            .collect::<Vec<_>>(),
+    )?;
+    Ok(writer.flush()?)
+}
+
+pub fn write_geojson(features: Vec<GeoJson>, mut writer: impl Write) -> Result<(), Error> {
+    serde_json::to_writer(&mut writer, &GeoJson::FeatureCollection { features })?;
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_and_geojson_roundtrip() {
+        let source = JsonSource {
+            path: PathBuf::from("src/trajectory_sample.json"),
+            geojson: false,
+        };
+        let trajectories = source
+            .trajectories()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(trajectories.len(), 1);
+        assert_eq!(trajectories[0].id.as_deref(), Some("1"));
+        assert_eq!(trajectories[0].points[0], Point::from((13.41, 75.13)));
+
+        let mut json = Vec::new();
+        write_json(&trajectories, &mut json).unwrap();
+        let JsonTrajectories::Many(written) = serde_json::from_slice(&json).unwrap() else {
+            panic!("expected an array of trajectories");
+        };
+        assert_eq!(written[0], JsonTrajectory::from(&trajectories[0]));
+
+        let points = &trajectories[0].points;
+        let mut geojson = Vec::new();
+        write_geojson(
+            segment_features(Some("1"), points, [(0..2, false), (1..4, true)]),
+            &mut geojson,
+        )
+        .unwrap();
+        let value: Value = serde_json::from_slice(&geojson).unwrap();
+        assert_eq!(value["features"][1]["properties"]["kind"], "reference");
+        assert_eq!(
+            value["features"][0]["geometry"]["coordinates"][0],
+            serde_json::json!([75.13, 13.41])
+        );
+
+        let read = serde_json::from_slice::<GeoJson>(&geojson)
+            .unwrap()
+            .trajectories()
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].id.as_deref(), Some("1"));
+        assert_eq!(read[1].points, points[1..4]);
+    }
+}
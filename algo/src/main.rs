@@ -1,38 +1,35 @@
-use crate::algorithm::{rest_main, Config, DpMode, Mode, RestMode};
+use clap::Parser;
+use std::process::ExitCode;
 
 pub mod algorithm;
+pub mod builder;
+pub mod cli;
+pub mod container;
+pub mod distance;
 pub mod dp;
 pub mod dtw_band;
+pub mod error;
+pub mod flatbuffer;
+pub mod json;
 pub mod max_dtw;
+pub mod metric;
+pub mod online;
+pub mod projection;
+pub mod reference_set;
 pub mod rest;
+pub mod sampling;
+pub mod source;
 pub mod spatial_filter;
+pub mod sweep;
+pub mod temporal;
+pub mod usage;
 
-fn run_config(conf: Config) -> Result<(), csv::Error> {
-    rest_main(conf.clone(), false, 1)?;
-    Ok(())
-}
-
-fn main() -> Result<(), csv::Error> {
-    let n = 100000;
-    let dtw_dist = 200;
-    let mut rest_mode = RestMode {
-        rs: 100,
-        compression_ratio: 3,
-        spatial_filter: true,
-        include_entire_trajectory: true,
-        k: 3,
-        error_point: 70,
-    };
-    let foo = vec![500, 600, 700, 800, 900, 1000];
-    for f in &foo[1..] {
-        rest_mode.rs = *f;
-        run_config(Config {
-            n,
-            max_dtw_dist: dtw_dist,
-            mode: Mode::Rest(rest_mode.clone()),
-            dtw_band: 0,
-        })?;
+fn main() -> ExitCode {
+    match cli::run(cli::Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
     }
-
-    Ok(())
 }
@@ -1,81 +1,505 @@
+use crate::metric::PointMetric;
 use crate::rest::Point;
-use std::collections::HashMap;
 
-pub fn max_dtw_band<'a>(
-    ta: &'a [Point],
-    tb: &'a [Point],
-    map: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
+/// Whether cell `(i, j)` lies within the band. A band of 0 is unbanded.
+fn in_band(i: usize, j: usize, band: usize) -> bool {
+    band == 0 || i.abs_diff(j) <= band
+}
+
+/// The prefix lengths the distance is read at. With a band, the last cell of the
+/// table may be unreachable, so the distance is read at the last cell the band reaches.
+fn band_end(n: usize, m: usize, band: usize) -> (usize, usize) {
+    if band == 0 {
+        (n, m)
+    } else {
+        (n.min(m + band), m.min(n + band))
+    }
+}
+
+/// How the distances of the matched points along a warping path add up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cost {
+    /// The largest distance, the bottleneck
+    Max,
+    /// The sum of the distances, classic DTW
+    Sum,
+}
+
+impl Cost {
+    fn combine(self, distance: f64, best: f64) -> f64 {
+        match self {
+            Cost::Max => distance.max(best),
+            Cost::Sum => distance + best,
+        }
+    }
+}
+
+/// A cell given the distance of its points and the cells diagonally before it, above it and to its left
+fn cell(cost: Cost, distance: f64, diagonal: f64, above: f64, left: f64) -> f64 {
+    cost.combine(distance, diagonal.min(above).min(left))
+}
+
+/// Max (bottleneck) DTW distance: the smallest possible largest distance between two
+/// matched points over all warping paths. Cells further than `band` from the diagonal
+/// are not matched, 0 is unbanded.
+pub fn max_dtw(ta: &[Point], tb: &[Point], band: usize, metric: PointMetric) -> f64 {
+    dtw(ta, tb, band, Cost::Max, metric)
+}
+
+/// DTW distance under `cost`, computed row by row in O(m) memory
+pub fn dtw(ta: &[Point], tb: &[Point], band: usize, cost: Cost, metric: PointMetric) -> f64 {
+    let (n, m) = band_end(ta.len(), tb.len(), band);
+    if n == 0 || m == 0 {
+        return if n == m { 0.0 } else { f64::MAX };
+    }
+    let mut previous = vec![f64::MAX; m];
+    let mut current = vec![f64::MAX; m];
+    for i in 0..n {
+        let (start, end) = if band == 0 {
+            (0, m)
+        } else {
+            (i.saturating_sub(band), (i + band + 1).min(m))
+        };
+        // The cells just outside the band are read by this row and the next one
+        if start > 0 {
+            current[start - 1] = f64::MAX;
+        }
+        if end < m {
+            current[end] = f64::MAX;
+        }
+        for j in start..end {
+            current[j] = match (i, j) {
+                (0, 0) => metric.distance(&ta[0], &tb[0]),
+                (0, _) => cost.combine(metric.distance(&ta[0], &tb[j]), current[j - 1]),
+                (_, 0) => cost.combine(metric.distance(&ta[i], &tb[0]), previous[0]),
+                _ => cell(
+                    cost,
+                    metric.distance(&ta[i], &tb[j]),
+                    previous[j - 1],
+                    previous[j],
+                    current[j - 1],
+                ),
+            };
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[m - 1]
+}
+
+/// The DTW distance between growing prefixes of two trajectories, extended one point at
+/// a time. Keeps the last row and the last column of the table, so that appending a
+/// point to either side costs O(length of the other side). The trajectories are not
+/// kept: every push takes them, and they must start with the points pushed so far.
+#[derive(Clone)]
+pub struct DtwState {
     band: usize,
-) -> f64 {
-    let y_range = (0..ta.len())
-        .map(|x| {
-            let min = (x as f32 - band as f32).max(0.0) as usize;
-            let max = (x + band + 1).min(tb.len());
-            (min, max)
+    cost: Cost,
+    metric: PointMetric,
+    /// The cells of the last row, one per point of `tb` pushed so far
+    row: Vec<f64>,
+    /// The cells of the last column, one per point of `ta` pushed so far
+    column: Vec<f64>,
+}
+
+impl DtwState {
+    /// Starts with both prefixes empty
+    pub fn new(band: usize, cost: Cost, metric: PointMetric) -> DtwState {
+        DtwState {
+            band,
+            cost,
+            metric,
+            row: Vec::new(),
+            column: Vec::new(),
+        }
+    }
+
+    /// Number of points of `ta` and `tb` pushed so far
+    pub fn len(&self) -> (usize, usize) {
+        (self.column.len(), self.row.len())
+    }
+
+    /// Appends the next point of `ta` and returns the new distance
+    pub fn push_a(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let (i, m) = self.len();
+        advance_line(i, m, self.band, self.cost, &mut self.row, |j| {
+            self.metric.distance(&ta[i], &tb[j])
+        });
+        self.column
+            .push(self.row.last().copied().unwrap_or(f64::MAX));
+        self.distance()
+    }
+
+    /// Appends the next point of `tb` and returns the new distance
+    pub fn push_b(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let (n, j) = self.len();
+        advance_line(j, n, self.band, self.cost, &mut self.column, |i| {
+            self.metric.distance(&ta[i], &tb[j])
+        });
+        self.row
+            .push(self.column.last().copied().unwrap_or(f64::MAX));
+        self.distance()
+    }
+
+    /// The distance between the prefixes pushed so far, equal to `dtw` of them
+    pub fn distance(&self) -> f64 {
+        let (n, m) = self.len();
+        let (end_n, end_m) = band_end(n, m, self.band);
+        if end_n == 0 || end_m == 0 {
+            return if end_n == end_m { 0.0 } else { f64::MAX };
+        }
+        // A band may end the distance early in the last row or the last column
+        if end_n < n {
+            self.column[end_n - 1]
+        } else {
+            self.row[end_m - 1]
+        }
+    }
+
+    /// A lower bound of the distance after pushing any more points of `ta` but none of `tb`.
+    /// Every path to a later row crosses the last one, unless the band already ends the
+    /// distance in an earlier row, where it stays.
+    pub fn push_a_bound(&self) -> f64 {
+        let (n, m) = self.len();
+        if self.band != 0 && n >= m + self.band {
+            return self.distance();
+        }
+        self.row_min()
+    }
+
+    fn row_min(&self) -> f64 {
+        self.row.iter().copied().fold(f64::MAX, f64::min)
+    }
+
+    /// The smallest cell of the last row and column. Cells never decrease along a path and
+    /// every path to a longer pair of prefixes crosses them, so no extension of this state
+    /// is closer than this.
+    pub fn frontier_min(&self) -> f64 {
+        if self.row.is_empty() || self.column.is_empty() {
+            return 0.0;
+        }
+        self.row_min()
+            .min(self.column.iter().copied().fold(f64::MAX, f64::min))
+    }
+}
+
+/// A lower bound of `max_dtw`, and so of `dtw` under any cost, from the first and last
+/// matched pairs, which every path contains
+pub fn endpoint_bound(ta: &[Point], tb: &[Point], band: usize, metric: PointMetric) -> f64 {
+    let (n, m) = band_end(ta.len(), tb.len(), band);
+    if n == 0 || m == 0 {
+        return 0.0;
+    }
+    metric
+        .distance(&ta[0], &tb[0])
+        .max(metric.distance(&ta[n - 1], &tb[m - 1]))
+}
+
+/// A lower bound of `max_dtw` in the manner of LB_Keogh: every point is matched to some
+/// point of the other side within the band, so it is at least as far as the bounding box
+/// of those points. Ignores the antimeridian.
+pub fn envelope_bound(ta: &[Point], tb: &[Point], band: usize, metric: PointMetric) -> f64 {
+    let (n, m) = band_end(ta.len(), tb.len(), band);
+    if n == 0 || m == 0 {
+        return 0.0;
+    }
+    envelope_side(&ta[..n], &tb[..m], band, metric).max(envelope_side(
+        &tb[..m],
+        &ta[..n],
+        band,
+        metric,
+    ))
+}
+
+fn envelope_side(ta: &[Point], tb: &[Point], band: usize, metric: PointMetric) -> f64 {
+    let whole = Envelope::of(tb);
+    ta.iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let envelope = if band == 0 {
+                whole
+            } else {
+                Envelope::of(&tb[i.saturating_sub(band)..(i + band + 1).min(tb.len())])
+            };
+            envelope.distance_bound(p, metric)
         })
-        .collect::<Vec<_>>();
-
-    let x_marks_the_spot = (ta.len().min(tb.len() + band), tb.len().min(ta.len() + band));
-
-    let foo = max_dtw(
-        &ta[..x_marks_the_spot.0],
-        &tb[..x_marks_the_spot.1],
-        map,
-        Some(&y_range),
-    );
-    foo
-}
-pub fn max_dtw<'a>(
-    ta: &'a [Point],
-    tb: &'a [Point],
-    map: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
-    y_range: Option<&Vec<(usize, usize)>>,
-) -> f64 {
-    match (ta, tb) {
-        ([], []) => 0.0,
-        ([.., a], [.., b]) => match y_range {
-            Some(y_range) => {
-                if y_range[ta.len() - 1].0 <= tb.len() - 1 && tb.len() - 1 < y_range[ta.len() - 1].1
-                {
-                    a.distance(b).max(q(ta, tb, map, Some(y_range)))
-                } else {
-                    f64::MAX
+        .fold(0.0, f64::max)
+}
+
+/// Whether `max_dtw(ta, tb, band, metric)` is shown to be at least `threshold` by a lower
+/// bound, trying the cheapest first
+pub fn lower_bound_reaches(
+    ta: &[Point],
+    tb: &[Point],
+    band: usize,
+    metric: PointMetric,
+    threshold: f64,
+) -> bool {
+    endpoint_bound(ta, tb, band, metric) >= threshold
+        || envelope_bound(ta, tb, band, metric) >= threshold
+}
+
+/// The bounding box of some points, in the units of `Point`
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    lat: (i32, i32),
+    lng: (i32, i32),
+}
+
+impl Envelope {
+    pub fn of(points: &[Point]) -> Envelope {
+        points.iter().fold(
+            Envelope {
+                lat: (i32::MAX, i32::MIN),
+                lng: (i32::MAX, i32::MIN),
+            },
+            |e, p| Envelope {
+                lat: (e.lat.0.min(p.lat), e.lat.1.max(p.lat)),
+                lng: (e.lng.0.min(p.lng), e.lng.1.max(p.lng)),
+            },
+        )
+    }
+
+    /// A lower bound of the distance in km from `p` to any point in the box. Takes the
+    /// smallest latitude and longitude differences and the box latitude furthest from the
+    /// equator, shrunk slightly so that rounding cannot put it above the distance to a
+    /// point on the box edge.
+    pub fn distance_bound(&self, p: &Point, metric: PointMetric) -> f64 {
+        let outside = |x: i32, (low, high): (i32, i32)| (low - x).max(x - high).max(0);
+        let d_lat = outside(p.lat, self.lat);
+        let d_lng = outside(p.lng, self.lng);
+        let furthest_lat = self.lat.0.abs().max(self.lat.1.abs());
+        metric.separation_bound(p, d_lat, d_lng, furthest_lat) * (1.0 - 1e-9)
+    }
+}
+
+/// Replaces row (or column) `index - 1` of `len` cells with row `index`, in place
+fn advance_line(
+    index: usize,
+    len: usize,
+    band: usize,
+    cost: Cost,
+    line: &mut Vec<f64>,
+    distance: impl Fn(usize) -> f64,
+) {
+    if index == 0 {
+        line.resize(len, f64::MAX);
+    }
+    // The cells of the previous line diagonally before and above the current cell,
+    // and the cell just computed to its left
+    let mut diagonal = f64::MAX;
+    let mut left = f64::MAX;
+    for (k, value) in line.iter_mut().enumerate() {
+        let above = *value;
+        *value = if !in_band(index, k, band) {
+            f64::MAX
+        } else {
+            match (index, k) {
+                (0, 0) => distance(0),
+                (0, _) => cost.combine(distance(k), left),
+                (_, 0) => cost.combine(distance(0), above),
+                _ => cell(cost, distance(k), diagonal, above, left),
+            }
+        };
+        diagonal = above;
+        left = *value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::sample_points;
+    use itertools::Itertools;
+
+    /// The memoized recursion the tables replaced, kept as the reference they must agree with
+    mod recursive {
+        use crate::rest::Point;
+        use std::collections::HashMap;
+
+        type Memo<'a> = HashMap<(&'a [Point], &'a [Point]), f64>;
+
+        pub fn max_dtw(ta: &[Point], tb: &[Point], band: usize) -> f64 {
+            let mut map = HashMap::new();
+            if band == 0 {
+                return max_dtw_memo(ta, tb, &mut map, None);
+            }
+            let y_range = (0..ta.len())
+                .map(|x| (x.saturating_sub(band), (x + band + 1).min(tb.len())))
+                .collect::<Vec<_>>();
+            let end = (ta.len().min(tb.len() + band), tb.len().min(ta.len() + band));
+            max_dtw_memo(&ta[..end.0], &tb[..end.1], &mut map, Some(&y_range))
+        }
+
+        fn max_dtw_memo<'a>(
+            ta: &'a [Point],
+            tb: &'a [Point],
+            map: &mut Memo<'a>,
+            y_range: Option<&Vec<(usize, usize)>>,
+        ) -> f64 {
+            match (ta, tb) {
+                ([], []) => 0.0,
+                ([.., a], [.., b]) => match y_range {
+                    Some(y_range) => {
+                        if y_range[ta.len() - 1].0 < tb.len()
+                            && tb.len() - 1 < y_range[ta.len() - 1].1
+                        {
+                            a.distance(b).max(q(ta, tb, map, Some(y_range)))
+                        } else {
+                            f64::MAX
+                        }
+                    }
+                    None => a.distance(b).max(q(ta, tb, map, None)),
+                },
+                _ => f64::MAX,
+            }
+        }
+
+        fn memo_or_calculate<'a>(
+            st: &'a [Point],
+            rt: &'a [Point],
+            map: &mut Memo<'a>,
+            y_range: Option<&Vec<(usize, usize)>>,
+        ) -> f64 {
+            match map.get(&(st, rt)) {
+                Some(&v) => v,
+                None => {
+                    let result = max_dtw_memo(st, rt, map, y_range);
+                    map.insert((st, rt), result);
+                    result
                 }
             }
-            None => a.distance(b).max(q(ta, tb, map, None)),
-        },
-        _ => f64::MAX,
-    }
-}
-fn memo_or_calculate<'a>(
-    st: &'a [Point],
-    rt: &'a [Point],
-    map: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
-    y_range: Option<&Vec<(usize, usize)>>,
-) -> f64 {
-    match map.get(&(st, rt)) {
-        Some(&v) => v,
-        None => {
-            let result = max_dtw(st, rt, map, y_range);
-            map.insert((&st, &rt), result);
-            result
-        }
-    }
-}
-fn q<'a>(
-    ta: &'a [Point],
-    tb: &'a [Point],
-    map: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
-    y_range: Option<&Vec<(usize, usize)>>,
-) -> f64 {
-    memo_or_calculate(except_last(ta), except_last(tb), map, y_range)
-        .min(memo_or_calculate(except_last(ta), tb, map, y_range))
-        .min(memo_or_calculate(ta, except_last(tb), map, y_range))
-}
-
-fn except_last(s: &[Point]) -> &[Point] {
-    match s {
-        [not_last @ .., _] => not_last,
-        _ => &[],
+        }
+
+        fn q<'a>(
+            ta: &'a [Point],
+            tb: &'a [Point],
+            map: &mut Memo<'a>,
+            y_range: Option<&Vec<(usize, usize)>>,
+        ) -> f64 {
+            memo_or_calculate(except_last(ta), except_last(tb), map, y_range)
+                .min(memo_or_calculate(except_last(ta), tb, map, y_range))
+                .min(memo_or_calculate(ta, except_last(tb), map, y_range))
+        }
+
+        fn except_last(s: &[Point]) -> &[Point] {
+            match s {
+                [not_last @ .., _] => not_last,
+                _ => &[],
+            }
+        }
+    }
+
+    #[test]
+    fn test_tables_and_states_equal_the_memoized_recursion() {
+        let trajectories = sample_points();
+        let metric = PointMetric::Haversine;
+        for (ta, tb) in trajectories.iter().tuple_windows() {
+            let (ta, tb) = (&ta[..ta.len().min(16)], &tb[..tb.len().min(12)]);
+            for band in [0, 2, 5] {
+                for (ta, tb) in [(ta, tb), (tb, ta)] {
+                    let expected = recursive::max_dtw(ta, tb, band);
+                    assert_eq!(max_dtw(ta, tb, band, metric), expected);
+                    assert!(endpoint_bound(ta, tb, band, metric) <= expected);
+                    assert!(envelope_bound(ta, tb, band, metric) <= expected);
+
+                    let mut state = DtwState::new(band, Cost::Max, metric);
+                    for push_a in [true, false].iter().cycle().take(ta.len() + tb.len()) {
+                        let (n, m) = state.len();
+                        let distance = if (*push_a && n < ta.len()) || m == tb.len() {
+                            state.push_a(ta, tb)
+                        } else {
+                            state.push_b(ta, tb)
+                        };
+                        let (n, m) = state.len();
+                        assert_eq!(distance, recursive::max_dtw(&ta[..n], &tb[..m], band));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dtw_state_matches_row_by_row() {
+        let ta = (0..8)
+            .map(|i| Point {
+                lat: 41_150_000 + i * 900,
+                lng: -8_610_000 + (i % 3) * 400,
+                time: None,
+            })
+            .collect::<Vec<_>>();
+        let tb = (0..6)
+            .map(|i| Point {
+                lat: 41_150_200 + i * 1300,
+                lng: -8_610_100,
+                time: None,
+            })
+            .collect::<Vec<_>>();
+
+        // Every point of the shorter line is matched to one at most 0.1 km away
+        let metric = PointMetric::Haversine;
+        assert!(max_dtw(&ta, &ta, 0, metric) == 0.0);
+        assert!(max_dtw(&ta, &tb, 0, metric) < 0.1);
+        assert_eq!(max_dtw(&ta, &[], 0, metric), f64::MAX);
+        assert_eq!(max_dtw(&[], &[], 3, metric), 0.0);
+
+        for (band, cost) in [0, 1, 2, 5]
+            .into_iter()
+            .cartesian_product([Cost::Max, Cost::Sum])
+        {
+            // Interleave both sides, running ahead on either one
+            for pattern in [
+                [true, true, false],
+                [false, false, true],
+                [true, false, false],
+            ] {
+                let mut state = DtwState::new(band, cost, metric);
+                assert_eq!(state.distance(), 0.0);
+                for push_a in pattern.iter().cycle().take(ta.len() + tb.len()) {
+                    let (n, m) = state.len();
+                    let distance = if (*push_a && n < ta.len()) || m == tb.len() {
+                        state.push_a(&ta, &tb)
+                    } else {
+                        state.push_b(&ta, &tb)
+                    };
+                    let (n, m) = state.len();
+                    assert_eq!(
+                        distance,
+                        dtw(&ta[..n], &tb[..m], band, cost, metric),
+                        "n {n}, m {m}, band {band}, {cost:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lower_bounds_do_not_exceed_the_distance() {
+        let trajectories = sample_points();
+        for (ta, tb) in trajectories.iter().tuple_windows() {
+            let (ta, tb) = (&ta[..ta.len().min(20)], &tb[..tb.len().min(15)]);
+            let planar = PointMetric::Planar { latitude: 41.15 };
+            for (band, metric) in [0, 2, 6].into_iter().cartesian_product([
+                PointMetric::Haversine,
+                PointMetric::Equirectangular,
+                planar,
+            ]) {
+                let distance = max_dtw(ta, tb, band, metric);
+                assert!(endpoint_bound(ta, tb, band, metric) <= distance);
+                assert!(envelope_bound(ta, tb, band, metric) <= distance);
+
+                let mut state = DtwState::new(band, Cost::Max, metric);
+                for _ in 0..tb.len() / 2 {
+                    state.push_b(ta, tb);
+                }
+                state.push_a(ta, tb);
+                let (frontier, push_a) = (state.frontier_min(), state.push_a_bound());
+                for n in 1..=ta.len() {
+                    assert!(push_a <= max_dtw(&ta[..n], &tb[..tb.len() / 2], band, metric));
+                    for m in tb.len() / 2..=tb.len() {
+                        assert!(frontier <= max_dtw(&ta[..n], &tb[..m], band, metric));
+                    }
+                }
+            }
+        }
     }
 }
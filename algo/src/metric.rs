@@ -0,0 +1,235 @@
+use crate::rest::Point;
+use serde::Deserialize;
+
+/// Mean earth radius in km, the one `haversine` uses
+pub const EARTH_RADIUS: f64 = 6371.0;
+
+/// Projected points are in centimeters
+const UNITS_PER_KM: f64 = 100000.0;
+
+fn radians(microdegrees: i32) -> f64 {
+    (microdegrees as f64 / 1000000.0).to_radians()
+}
+
+/// How the distance between two points is measured. Distances are in km.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointMetric {
+    /// Great circle distance on a sphere
+    #[default]
+    Haversine,
+    /// Plane distance after scaling longitudes by the cosine of the mean latitude of
+    /// the two points. Accurate to well below a meter at the scale of a city.
+    Equirectangular,
+    /// Euclidean distance in an equirectangular projection with standard parallel
+    /// `latitude` in degrees, exact in that planar CRS
+    Planar { latitude: f64 },
+    /// Euclidean distance between points projected to centimeters, see `projection`
+    #[serde(skip)]
+    Projected,
+}
+
+impl PointMetric {
+    pub fn distance(self, a: &Point, b: &Point) -> f64 {
+        match self {
+            PointMetric::Haversine => a.haversine(b),
+            _ => {
+                let (east, north) = self.offset(a, b);
+                east.hypot(north)
+            }
+        }
+    }
+
+    /// The east and north offsets in km from `from` to `to` in the plane the metric
+    /// measures in, an equirectangular one around the two points for haversine
+    pub fn offset(self, from: &Point, to: &Point) -> (f64, f64) {
+        let d_lat = radians(to.lat) - radians(from.lat);
+        let d_lng = radians(to.lng) - radians(from.lng);
+        match self {
+            PointMetric::Haversine | PointMetric::Equirectangular => {
+                let mean_lat = radians(from.lat) / 2.0 + radians(to.lat) / 2.0;
+                (EARTH_RADIUS * d_lng * mean_lat.cos(), EARTH_RADIUS * d_lat)
+            }
+            PointMetric::Planar { latitude } => (
+                EARTH_RADIUS * d_lng * latitude.to_radians().cos(),
+                EARTH_RADIUS * d_lat,
+            ),
+            PointMetric::Projected => (
+                (to.lng - from.lng) as f64 / UNITS_PER_KM,
+                (to.lat - from.lat) as f64 / UNITS_PER_KM,
+            ),
+        }
+    }
+
+    /// A lower bound of the distance from `p` to any point whose coordinates differ from
+    /// it by at least `d_lat` and `d_lng`, with latitudes up to `furthest_lat` from the
+    /// equator. All in the units of `Point`.
+    pub fn separation_bound(self, p: &Point, d_lat: i32, d_lng: i32, furthest_lat: i32) -> f64 {
+        let lat = radians(p.lat);
+        let (lat_gap, lng_gap, furthest) = (radians(d_lat), radians(d_lng), radians(furthest_lat));
+        match self {
+            PointMetric::Haversine => {
+                let a = (lat_gap / 2.0).sin().powi(2)
+                    + (lng_gap / 2.0).sin().powi(2) * lat.cos() * furthest.cos();
+                2.0 * EARTH_RADIUS * a.sqrt().atan2((1.0 - a).sqrt())
+            }
+            // The mean latitude lies between the two, so its cosine is at least the smaller one
+            PointMetric::Equirectangular => {
+                EARTH_RADIUS * lat_gap.hypot(lng_gap * lat.abs().max(furthest).cos())
+            }
+            PointMetric::Planar { latitude } => {
+                EARTH_RADIUS * lat_gap.hypot(lng_gap * latitude.to_radians().cos())
+            }
+            PointMetric::Projected => (d_lat as f64).hypot(d_lng as f64) / UNITS_PER_KM,
+        }
+    }
+
+    /// Half the latitude and longitude extents, in the units of `Point`, of a box around
+    /// `center` that reaches `distance` km from it in every direction
+    pub fn half_extent(self, center: &Point, distance: f64) -> (f64, f64) {
+        let microdegrees = (distance / EARTH_RADIUS).to_degrees() * 1000000.0;
+        match self {
+            PointMetric::Haversine | PointMetric::Equirectangular => {
+                (microdegrees, microdegrees / radians(center.lat).cos())
+            }
+            PointMetric::Planar { latitude } => {
+                (microdegrees, microdegrees / latitude.to_radians().cos())
+            }
+            PointMetric::Projected => (distance * UNITS_PER_KM, distance * UNITS_PER_KM),
+        }
+    }
+
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            PointMetric::Haversine => "",
+            PointMetric::Equirectangular => "-EQR",
+            PointMetric::Planar { .. } => "-PLANAR",
+            PointMetric::Projected => "-PROJECTED",
+        }
+    }
+
+    /// Tag stored in reference set files, the standard parallel is stored next to it
+    pub fn code(self) -> u8 {
+        match self {
+            PointMetric::Haversine => 0,
+            PointMetric::Equirectangular => 1,
+            PointMetric::Planar { .. } => 2,
+            PointMetric::Projected => 3,
+        }
+    }
+
+    pub fn from_code(code: u8, latitude: f64) -> Option<PointMetric> {
+        match code {
+            0 => Some(PointMetric::Haversine),
+            1 => Some(PointMetric::Equirectangular),
+            2 => Some(PointMetric::Planar { latitude }),
+            3 => Some(PointMetric::Projected),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            PointMetric::Haversine => String::from("haversine"),
+            PointMetric::Equirectangular => String::from("equirectangular"),
+            PointMetric::Planar { latitude } => format!("planar at {latitude}"),
+            PointMetric::Projected => String::from("projected"),
+        }
+    }
+}
+
+/// How far a metric strays from haversine over a set of point pairs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApproximationError {
+    pub pairs: usize,
+    pub mean_abs_m: f64,
+    pub max_abs_m: f64,
+    /// Largest error relative to the haversine distance, over pairs at least a meter apart
+    pub max_relative: f64,
+}
+
+/// Compares `metric` on `points` to haversine on the same points of `trajectories`, which
+/// differ when `points` are projected. Takes the consecutive points of every trajectory,
+/// the distances the DTW tables are made of, and the first point against every other,
+/// which covers the longest distances within a trajectory.
+pub fn approximation_error(
+    metric: PointMetric,
+    points: &[Vec<Point>],
+    trajectories: &[Vec<Point>],
+) -> ApproximationError {
+    let mut error = ApproximationError {
+        pairs: 0,
+        mean_abs_m: 0.0,
+        max_abs_m: 0.0,
+        max_relative: 0.0,
+    };
+    let mut total = 0.0;
+    for (p, t) in points.iter().zip(trajectories) {
+        let pairs = (1..t.len()).map(|i| (i - 1, i));
+        let spans = (2..t.len()).map(|i| (0, i));
+        for (i, j) in pairs.chain(spans) {
+            let exact = t[i].haversine(&t[j]);
+            let abs = (metric.distance(&p[i], &p[j]) - exact).abs() * 1000.0;
+            total += abs;
+            error.pairs += 1;
+            error.max_abs_m = error.max_abs_m.max(abs);
+            if exact >= 0.001 {
+                error.max_relative = error.max_relative.max(abs / (exact * 1000.0));
+            }
+        }
+    }
+    if error.pairs > 0 {
+        error.mean_abs_m = total / error.pairs as f64;
+    }
+    error
+}
+
+/// The mean latitude of the points in degrees, a standard parallel for `PointMetric::Planar`
+pub fn mean_latitude(trajectories: &[Vec<Point>]) -> Option<f64> {
+    let (sum, count) = trajectories
+        .iter()
+        .flatten()
+        .fold((0.0, 0usize), |(sum, count), p| {
+            (sum + p.lat as f64 / 1000000.0, count + 1)
+        });
+    (count > 0).then(|| sum / count as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::sample_points;
+
+    #[test]
+    fn test_metrics_agree_at_city_scale() {
+        let a = Point {
+            lat: 41_150_000,
+            lng: -8_610_000,
+            time: None,
+        };
+        let b = Point {
+            lat: 41_160_000,
+            lng: -8_600_000,
+            time: None,
+        };
+        assert_eq!(PointMetric::Haversine.distance(&a, &b), a.distance(&b));
+        let planar = PointMetric::Planar { latitude: 41.155 };
+        for metric in [PointMetric::Equirectangular, planar] {
+            assert!((metric.distance(&a, &b) - a.distance(&b)).abs() < 1e-5);
+            assert_eq!(metric.distance(&a, &a), 0.0);
+        }
+
+        let trajectories = sample_points();
+        let latitude = mean_latitude(&trajectories).unwrap();
+        assert!((41.0..41.3).contains(&latitude));
+        assert_eq!(
+            approximation_error(PointMetric::Haversine, &trajectories, &trajectories).max_abs_m,
+            0.0
+        );
+        let error = approximation_error(PointMetric::Equirectangular, &trajectories, &trajectories);
+        assert!(error.pairs > 1000);
+        assert!(error.max_abs_m < 1.0 && error.max_relative < 0.01);
+        assert!(error.mean_abs_m <= error.max_abs_m);
+    }
+}
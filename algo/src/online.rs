@@ -0,0 +1,323 @@
+use crate::dp::{perpendicular_distance, synchronized_distance};
+use crate::metric::PointMetric;
+use crate::rest::Point;
+
+/// Buffer size of `SquishE` in the DP mode harness
+pub const SQUISH_CAPACITY: usize = 100;
+
+/// A line simplifier fed one point at a time, which hands over the points it keeps as
+/// soon as they are final. Like `dp`, `epsilon` is in km under the metric it is built with.
+pub trait OnlineSimplifier {
+    /// Takes the next point of the trajectory and appends the points now known to be kept to `kept`
+    fn push(&mut self, point: Point, kept: &mut Vec<Point>);
+
+    /// Ends the trajectory, appending the points still held, and readies the simplifier for the next one
+    fn finish(&mut self, kept: &mut Vec<Point>);
+}
+
+/// Feeds a whole trajectory through `simplifier`
+pub fn simplify_online(simplifier: &mut dyn OnlineSimplifier, polyline: &[Point]) -> Vec<Point> {
+    let mut kept = Vec::new();
+    for p in polyline {
+        simplifier.push(p.clone(), &mut kept);
+    }
+    simplifier.finish(&mut kept);
+    kept
+}
+
+/// Time of a point in seconds, or its index in the trajectory when it has none
+fn time_or_index(point: &Point, index: usize) -> f64 {
+    point.time.map_or(index as f64, |t| t as f64)
+}
+
+/// Opening Window: grows a segment from the last kept point for as long as every point it
+/// skips is within epsilon of it, then keeps the last point that closed a valid window
+pub struct OpeningWindow {
+    epsilon: f64,
+    metric: PointMetric,
+    anchor: Option<Point>,
+    /// The points after the anchor
+    window: Vec<Point>,
+}
+
+impl OpeningWindow {
+    pub fn new(epsilon: f64, metric: PointMetric) -> OpeningWindow {
+        OpeningWindow {
+            epsilon,
+            metric,
+            anchor: None,
+            window: Vec::new(),
+        }
+    }
+}
+
+impl OnlineSimplifier for OpeningWindow {
+    fn push(&mut self, point: Point, kept: &mut Vec<Point>) {
+        let Some(anchor) = &self.anchor else {
+            kept.push(point.clone());
+            self.anchor = Some(point);
+            return;
+        };
+        let fits = self
+            .window
+            .iter()
+            .all(|p| perpendicular_distance(p, anchor, &point, self.metric) <= self.epsilon);
+        if !fits {
+            let float = self.window.pop().expect("an empty window always fits");
+            kept.push(float.clone());
+            self.anchor = Some(float);
+            self.window.clear();
+        }
+        self.window.push(point);
+    }
+
+    fn finish(&mut self, kept: &mut Vec<Point>) {
+        kept.extend(self.window.pop());
+        self.anchor = None;
+        self.window.clear();
+    }
+}
+
+/// Dead Reckoning: predicts every point from the position and velocity at the last kept
+/// point, and keeps the points that stray more than epsilon from the prediction. Untimed
+/// points are taken to be sampled at a constant rate.
+pub struct DeadReckoning {
+    epsilon: f64,
+    metric: PointMetric,
+    pushed: usize,
+    /// The last kept point and its time
+    anchor: Option<(Point, f64)>,
+    /// East and north km per second at the anchor, known once a point follows it
+    velocity: Option<(f64, f64)>,
+    /// The last point pushed and its time, unless it was kept
+    last: Option<(Point, f64)>,
+}
+
+impl DeadReckoning {
+    pub fn new(epsilon: f64, metric: PointMetric) -> DeadReckoning {
+        DeadReckoning {
+            epsilon,
+            metric,
+            pushed: 0,
+            anchor: None,
+            velocity: None,
+            last: None,
+        }
+    }
+
+    fn velocity(&self, from: &(Point, f64), to: &(Point, f64)) -> (f64, f64) {
+        let (east, north) = self.metric.offset(&from.0, &to.0);
+        let elapsed = to.1 - from.1;
+        if elapsed > 0.0 {
+            (east / elapsed, north / elapsed)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
+impl OnlineSimplifier for DeadReckoning {
+    fn push(&mut self, point: Point, kept: &mut Vec<Point>) {
+        let time = time_or_index(&point, self.pushed);
+        let current = (point, time);
+        self.pushed += 1;
+        let Some(anchor) = &self.anchor else {
+            kept.push(current.0.clone());
+            self.anchor = Some(current);
+            return;
+        };
+        let Some((east, north)) = self.velocity else {
+            self.velocity = Some(self.velocity(anchor, &current));
+            self.last = Some(current);
+            return;
+        };
+        let elapsed = current.1 - anchor.1;
+        let (x, y) = self.metric.offset(&anchor.0, &current.0);
+        if (x - east * elapsed).hypot(y - north * elapsed) > self.epsilon {
+            // The motion since the previous point is the best guess of the motion ahead
+            let previous = self.last.as_ref().unwrap_or(anchor);
+            self.velocity = Some(self.velocity(previous, &current));
+            kept.push(current.0.clone());
+            self.anchor = Some(current);
+            self.last = None;
+        } else {
+            self.last = Some(current);
+        }
+    }
+
+    fn finish(&mut self, kept: &mut Vec<Point>) {
+        kept.extend(self.last.take().map(|(p, _)| p));
+        self.pushed = 0;
+        self.anchor = None;
+        self.velocity = None;
+    }
+}
+
+/// SQUISH-E with an error bound: keeps a buffer of points, each with a priority that
+/// bounds the synchronized Euclidean distance of the points removed around it, and
+/// removes the point of least priority while that priority is within epsilon. When the
+/// buffer grows beyond its capacity its oldest point is kept, which bounds memory and
+/// latency at the cost of fewer removals.
+pub struct SquishE {
+    epsilon: f64,
+    metric: PointMetric,
+    capacity: usize,
+    pushed: usize,
+    buffer: Vec<SquishEntry>,
+}
+
+struct SquishEntry {
+    point: Point,
+    time: f64,
+    /// The largest priority of a point removed next to this one
+    inherited: f64,
+    priority: f64,
+}
+
+impl SquishE {
+    pub fn new(epsilon: f64, metric: PointMetric, capacity: usize) -> SquishE {
+        SquishE {
+            epsilon,
+            metric,
+            capacity: capacity.max(3),
+            pushed: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The end points of the buffer are never removed
+    fn update_priority(&mut self, i: usize) {
+        if i == 0 || i + 1 >= self.buffer.len() {
+            self.buffer[i].priority = f64::INFINITY;
+            return;
+        }
+        let (before, entry, after) = (&self.buffer[i - 1], &self.buffer[i], &self.buffer[i + 1]);
+        let span = after.time - before.time;
+        let ratio = if span > 0.0 {
+            (entry.time - before.time) / span
+        } else {
+            0.0
+        };
+        let sed = synchronized_distance(
+            &entry.point,
+            &before.point,
+            &after.point,
+            ratio,
+            self.metric,
+        );
+        self.buffer[i].priority = entry.inherited + sed;
+    }
+}
+
+impl OnlineSimplifier for SquishE {
+    fn push(&mut self, point: Point, kept: &mut Vec<Point>) {
+        let time = time_or_index(&point, self.pushed);
+        self.pushed += 1;
+        self.buffer.push(SquishEntry {
+            point,
+            time,
+            inherited: 0.0,
+            priority: f64::INFINITY,
+        });
+        if self.buffer.len() >= 3 {
+            self.update_priority(self.buffer.len() - 2);
+        }
+        while let Some((i, priority)) = self
+            .buffer
+            .iter()
+            .map(|entry| entry.priority)
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|&(_, priority)| priority <= self.epsilon)
+        {
+            self.buffer.remove(i);
+            for j in [i - 1, i] {
+                self.buffer[j].inherited = self.buffer[j].inherited.max(priority);
+                self.update_priority(j);
+            }
+        }
+        if self.buffer.len() > self.capacity {
+            kept.push(self.buffer.remove(0).point);
+            self.update_priority(0);
+        }
+    }
+
+    fn finish(&mut self, kept: &mut Vec<Point>) {
+        kept.extend(self.buffer.drain(..).map(|entry| entry.point));
+        self.pushed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::longest_timed_trip;
+    use crate::temporal::sed;
+
+    const EPSILON: f64 = 0.1;
+    const METRIC: PointMetric = PointMetric::Haversine;
+
+    fn simplifiers() -> Vec<Box<dyn OnlineSimplifier>> {
+        vec![
+            Box::new(OpeningWindow::new(EPSILON, METRIC)),
+            Box::new(DeadReckoning::new(EPSILON, METRIC)),
+            Box::new(SquishE::new(EPSILON, METRIC, 10)),
+        ]
+    }
+
+    #[test]
+    fn test_online_simplifiers_emit_as_they_go() {
+        let points = longest_timed_trip();
+        for mut simplifier in simplifiers() {
+            let mut kept = Vec::new();
+            let mut emitted_early = false;
+            for p in &points {
+                simplifier.push(p.clone(), &mut kept);
+                emitted_early |= kept.len() > 1;
+            }
+            simplifier.finish(&mut kept);
+            assert!(emitted_early);
+            assert!(kept.len() > 2 && kept.len() < points.len());
+            // Finishing readies the simplifier for the next trajectory
+            assert_eq!(simplify_online(simplifier.as_mut(), &points), kept);
+        }
+    }
+
+    #[test]
+    fn test_online_simplifiers_keep_a_subsequence_with_both_ends() {
+        let points = longest_timed_trip();
+        for mut simplifier in simplifiers() {
+            let kept = simplify_online(simplifier.as_mut(), &points);
+            assert_eq!(kept[0], points[0]);
+            assert_eq!(kept.last(), points.last());
+            let mut rest = points.iter();
+            assert!(kept.iter().all(|k| rest.any(|p| p == k)));
+        }
+        assert_eq!(
+            simplify_online(&mut DeadReckoning::new(EPSILON, METRIC), &[]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_squish_e_priorities_bound_the_synchronized_distance() {
+        let points = longest_timed_trip();
+        let squished = simplify_online(&mut SquishE::new(EPSILON, METRIC, usize::MAX), &points);
+        assert!(sed(&points, &squished, METRIC) <= EPSILON);
+    }
+
+    #[test]
+    fn test_opening_window_keeps_every_point_near_its_segment() {
+        let points = longest_timed_trip();
+        let window = simplify_online(&mut OpeningWindow::new(EPSILON, METRIC), &points);
+        let mut kept = window.iter().peekable();
+        let mut segment = (kept.next().unwrap(), kept.peek().cloned());
+        for p in &points {
+            if Some(p) == segment.1 {
+                segment = (kept.next().unwrap(), kept.peek().cloned());
+            } else if let Some(end) = segment.1 {
+                assert!(perpendicular_distance(p, segment.0, end, METRIC) <= EPSILON);
+            }
+        }
+    }
+}
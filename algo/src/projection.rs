@@ -0,0 +1,348 @@
+use crate::metric::PointMetric;
+use crate::rest::Point;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// WGS84 semi-major axis in meters and flattening
+const SEMI_MAJOR_AXIS: f64 = 6378137.0;
+const FLATTENING: f64 = 1.0 / 298.257223563;
+
+/// Projected coordinates are held in `Point` in centimeters, `lat` the northing and `lng`
+/// the easting, which keeps UTM northings within an i32
+const UNITS_PER_METER: f64 = 100.0;
+
+/// Which projection points are held in while compressing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectionKind {
+    /// Geographic coordinates in microdegrees
+    #[default]
+    None,
+    /// Transverse Mercator tangent to the meridian of the centroid, which maps to (0, 0)
+    Local,
+    /// The UTM zone of the centroid
+    Utm,
+}
+
+impl ProjectionKind {
+    /// The projection of this kind around the centroid of the points
+    pub fn fit(self, trajectories: &[Vec<Point>]) -> Projection {
+        Projection {
+            kind: self,
+            origin: centroid(trajectories),
+        }
+    }
+
+    /// The metric points are compared under: Euclidean once projected, `geographic` otherwise
+    pub fn metric(self, geographic: PointMetric) -> PointMetric {
+        match self {
+            ProjectionKind::None => geographic,
+            ProjectionKind::Local | ProjectionKind::Utm => PointMetric::Projected,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProjectionKind::None => "none",
+            ProjectionKind::Local => "local",
+            ProjectionKind::Utm => "utm",
+        }
+    }
+
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            ProjectionKind::None => "",
+            ProjectionKind::Local => "-LOCAL",
+            ProjectionKind::Utm => "-UTM",
+        }
+    }
+
+    /// Tag stored in reference set files
+    pub fn code(self) -> u8 {
+        match self {
+            ProjectionKind::None => 0,
+            ProjectionKind::Local => 1,
+            ProjectionKind::Utm => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<ProjectionKind> {
+        match code {
+            0 => Some(ProjectionKind::None),
+            1 => Some(ProjectionKind::Local),
+            2 => Some(ProjectionKind::Utm),
+            _ => None,
+        }
+    }
+}
+
+/// A projection fitted to a dataset. `origin` is the geographic point the projection is
+/// placed around: the tangent meridian and (0, 0) of a local projection, or the point
+/// whose UTM zone is used. Projecting with `ProjectionKind::None` leaves points unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Projection {
+    pub kind: ProjectionKind,
+    pub origin: Point,
+}
+
+impl Projection {
+    pub fn none() -> Projection {
+        Projection {
+            kind: ProjectionKind::None,
+            origin: Point {
+                lat: 0,
+                lng: 0,
+                time: None,
+            },
+        }
+    }
+
+    /// The UTM zone (1 to 60) and hemisphere of the origin, ignoring the Norway and Svalbard exceptions
+    pub fn utm_zone(&self) -> (u8, bool) {
+        let lng = self.origin.lng as f64 / 1000000.0;
+        let zone = (((lng + 180.0) / 6.0).floor() as i32 + 1).clamp(1, 60);
+        (zone as u8, self.origin.lat >= 0)
+    }
+
+    fn transverse_mercator(&self) -> Option<TransverseMercator> {
+        match self.kind {
+            ProjectionKind::None => None,
+            ProjectionKind::Local => {
+                let mut tm = TransverseMercator::new(self.origin.lng as f64 / 1000000.0, 1.0);
+                let (easting, northing) = tm.forward(&self.origin);
+                tm.false_easting = -easting;
+                tm.false_northing = -northing;
+                Some(tm)
+            }
+            ProjectionKind::Utm => {
+                let (zone, north) = self.utm_zone();
+                let mut tm = TransverseMercator::new(zone as f64 * 6.0 - 183.0, 0.9996);
+                tm.false_easting = 500000.0;
+                tm.false_northing = if north { 0.0 } else { 10000000.0 };
+                Some(tm)
+            }
+        }
+    }
+
+    pub fn project_all(&self, trajectories: Vec<Vec<Point>>) -> Vec<Vec<Point>> {
+        self.map_all(trajectories, |tm, p| tm.project(p))
+    }
+
+    pub fn unproject_all(&self, trajectories: Vec<Vec<Point>>) -> Vec<Vec<Point>> {
+        self.map_all(trajectories, |tm, p| tm.unproject(p))
+    }
+
+    /// The inverse of `project_all` for a single point, see `CompressedTrajectory::map_raw_points`
+    pub fn unprojector(&self) -> impl Fn(&Point) -> Point {
+        let tm = self.transverse_mercator();
+        move |p| match &tm {
+            Some(tm) => tm.unproject(p),
+            None => p.clone(),
+        }
+    }
+
+    fn map_all(
+        &self,
+        trajectories: Vec<Vec<Point>>,
+        f: impl Fn(&TransverseMercator, &Point) -> Point,
+    ) -> Vec<Vec<Point>> {
+        match self.transverse_mercator() {
+            None => trajectories,
+            Some(tm) => trajectories
+                .into_iter()
+                .map(|t| t.iter().map(|p| f(&tm, p)).collect())
+                .collect(),
+        }
+    }
+}
+
+/// The mean position of the points, ignoring the antimeridian
+fn centroid(trajectories: &[Vec<Point>]) -> Point {
+    let (lat, lng, count) = trajectories
+        .iter()
+        .flatten()
+        .fold((0i64, 0i64, 0i64), |(lat, lng, count), p| {
+            (lat + p.lat as i64, lng + p.lng as i64, count + 1)
+        });
+    if count == 0 {
+        return Point {
+            lat: 0,
+            lng: 0,
+            time: None,
+        };
+    }
+    Point {
+        lat: (lat / count) as i32,
+        lng: (lng / count) as i32,
+        time: None,
+    }
+}
+
+/// Transverse Mercator on the WGS84 ellipsoid with Krüger's series to third order in
+/// the third flattening, accurate to a millimeter within a few thousand km of the
+/// central meridian
+struct TransverseMercator {
+    central_meridian: f64,
+    false_easting: f64,
+    false_northing: f64,
+    /// Rectifying radius times the scale
+    radius: f64,
+    eccentricity: f64,
+    alpha: [f64; 3],
+    beta: [f64; 3],
+    delta: [f64; 3],
+}
+
+impl TransverseMercator {
+    /// A projection with central meridian `central_meridian` in degrees and scale `scale` on it
+    fn new(central_meridian: f64, scale: f64) -> TransverseMercator {
+        let n = FLATTENING / (2.0 - FLATTENING);
+        let (n2, n3) = (n * n, n * n * n);
+        TransverseMercator {
+            central_meridian: central_meridian.to_radians(),
+            false_easting: 0.0,
+            false_northing: 0.0,
+            radius: scale * SEMI_MAJOR_AXIS / (1.0 + n) * (1.0 + n2 / 4.0 + n2 * n2 / 64.0),
+            eccentricity: 2.0 * n.sqrt() / (1.0 + n),
+            alpha: [
+                n / 2.0 - 2.0 * n2 / 3.0 + 5.0 * n3 / 16.0,
+                13.0 * n2 / 48.0 - 3.0 * n3 / 5.0,
+                61.0 * n3 / 240.0,
+            ],
+            beta: [
+                n / 2.0 - 2.0 * n2 / 3.0 + 37.0 * n3 / 96.0,
+                n2 / 48.0 + n3 / 15.0,
+                17.0 * n3 / 480.0,
+            ],
+            delta: [
+                2.0 * n - 2.0 * n2 / 3.0 - 2.0 * n3,
+                7.0 * n2 / 3.0 - 8.0 * n3 / 5.0,
+                56.0 * n3 / 15.0,
+            ],
+        }
+    }
+
+    /// Easting and northing in meters
+    fn forward(&self, p: &Point) -> (f64, f64) {
+        let lat = (p.lat as f64 / 1000000.0).to_radians();
+        let lng = (p.lng as f64 / 1000000.0).to_radians() - self.central_meridian;
+        let e = self.eccentricity;
+        let t = (lat.sin().atanh() - e * (e * lat.sin()).atanh()).sinh();
+        let xi = t.atan2(lng.cos());
+        let eta = (lng.sin() / (1.0 + t * t).sqrt()).atanh();
+        let (mut x, mut y) = (eta, xi);
+        for (j, alpha) in self.alpha.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            x += alpha * (k * xi).cos() * (k * eta).sinh();
+            y += alpha * (k * xi).sin() * (k * eta).cosh();
+        }
+        (
+            self.false_easting + self.radius * x,
+            self.false_northing + self.radius * y,
+        )
+    }
+
+    fn inverse(&self, easting: f64, northing: f64) -> Point {
+        let xi = (northing - self.false_northing) / self.radius;
+        let eta = (easting - self.false_easting) / self.radius;
+        let (mut xi_prime, mut eta_prime) = (xi, eta);
+        for (j, beta) in self.beta.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            xi_prime -= beta * (k * xi).sin() * (k * eta).cosh();
+            eta_prime -= beta * (k * xi).cos() * (k * eta).sinh();
+        }
+        let chi = (xi_prime.sin() / eta_prime.cosh()).asin();
+        let mut lat = chi;
+        for (j, delta) in self.delta.iter().enumerate() {
+            lat += delta * (2.0 * (j + 1) as f64 * chi).sin();
+        }
+        let lng = self.central_meridian + eta_prime.sinh().atan2(xi_prime.cos());
+        Point {
+            lat: (lat.to_degrees() * 1000000.0).round() as i32,
+            lng: (lng.to_degrees() * 1000000.0).round() as i32,
+            time: None,
+        }
+    }
+
+    fn project(&self, p: &Point) -> Point {
+        let (easting, northing) = self.forward(p);
+        Point {
+            lat: (northing * UNITS_PER_METER).round() as i32,
+            lng: (easting * UNITS_PER_METER).round() as i32,
+            time: p.time,
+        }
+    }
+
+    fn unproject(&self, p: &Point) -> Point {
+        Point {
+            time: p.time,
+            ..self.inverse(
+                p.lng as f64 / UNITS_PER_METER,
+                p.lat as f64 / UNITS_PER_METER,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::sample_points;
+
+    #[test]
+    fn test_projections_roundtrip_and_keep_distances() {
+        let trajectories = sample_points();
+
+        let utm = ProjectionKind::Utm.fit(&trajectories);
+        // Porto lies in zone 29 north
+        assert_eq!(utm.utm_zone(), (29, true));
+        // The easting of a point on the central meridian of its zone
+        let meridian = Projection {
+            kind: ProjectionKind::Utm,
+            origin: Point {
+                lat: 0,
+                lng: -9_000_000,
+                time: None,
+            },
+        };
+        let projected = meridian.project_all(vec![vec![meridian.origin.clone()]]);
+        assert_eq!(
+            projected[0][0],
+            Point {
+                lat: 0,
+                lng: 50000000,
+                time: None,
+            }
+        );
+
+        for kind in [ProjectionKind::Local, ProjectionKind::Utm] {
+            let projection = kind.fit(&trajectories);
+            let projected = projection.project_all(trajectories.clone());
+            for (t, p) in trajectories.iter().zip(&projected) {
+                for (a, b) in t.iter().zip(p).step_by(7) {
+                    let back = projection.unprojector()(b);
+                    assert!((a.lat - back.lat).abs() <= 1 && (a.lng - back.lng).abs() <= 1);
+                }
+                // Haversine is on a sphere, so allow for the flattening of the ellipsoid
+                let (a, b) = (&t[0], &t[t.len() - 1]);
+                let exact = a.haversine(b);
+                let planar = PointMetric::Projected.distance(&p[0], &p[p.len() - 1]);
+                assert!((planar - exact).abs() <= 0.005 * exact + 1e-5, "{kind:?}");
+            }
+        }
+        let local = ProjectionKind::Local.fit(&trajectories);
+        assert_eq!(
+            local.project_all(vec![vec![local.origin.clone()]])[0][0],
+            Point {
+                lat: 0,
+                lng: 0,
+                time: None
+            }
+        );
+        assert_eq!(
+            Projection::none().project_all(trajectories.clone()),
+            trajectories
+        );
+    }
+}
@@ -0,0 +1,620 @@
+use crate::algorithm::{Config, RestMode};
+use crate::container::{
+    invalid_data, read_points, read_times, read_u16, read_u32, write_points, write_times,
+};
+use crate::distance::DistanceKind;
+use crate::metric::PointMetric;
+use crate::projection::{Projection, ProjectionKind};
+use crate::rest::{EncodedTrajectory, Point};
+use crate::spatial_filter::{bulk_load_reference_set, PointWithIndexReference};
+use crate::temporal::is_timed;
+use clap::ValueEnum;
+use itertools::Itertools;
+use rstar::RTree;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: [u8; 4] = *b"RSTR";
+pub const VERSION: u16 = 1;
+
+/// A loaded set in the projection it was built in, with its R-tree and that projection
+pub type LoadedReferenceSet = (
+    Vec<Vec<Point>>,
+    Option<RTree<PointWithIndexReference>>,
+    Projection,
+);
+
+/// Parameters a reference set was built under. A set is only reused when the
+/// parameters that affect encoding match, see `ReferenceSetMetadata::mismatches`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceSetMetadata {
+    pub max_dtw_dist: i32,
+    pub dtw_band: usize,
+    pub distance: DistanceKind,
+    pub metric: PointMetric,
+    /// The projection the set was built in. Sets are stored in lat/lng and projected again on loading.
+    pub projection: Projection,
+    pub k: usize,
+    pub spatial_filter: bool,
+    pub error_point: i32,
+    pub compression_ratio: i32,
+    pub include_entire_trajectory: bool,
+    pub sample_size: usize,
+}
+
+impl ReferenceSetMetadata {
+    pub fn new(
+        conf: &Config,
+        rest_conf: &RestMode,
+        sample_size: usize,
+        projection: Projection,
+    ) -> ReferenceSetMetadata {
+        ReferenceSetMetadata {
+            max_dtw_dist: conf.max_dtw_dist,
+            dtw_band: conf.dtw_band,
+            distance: conf.distance,
+            metric: conf.metric,
+            projection,
+            k: rest_conf.k,
+            spatial_filter: rest_conf.spatial_filter,
+            error_point: rest_conf.error_point,
+            compression_ratio: rest_conf.compression_ratio,
+            include_entire_trajectory: rest_conf.include_entire_trajectory,
+            sample_size,
+        }
+    }
+
+    /// Describes the encoding parameters that differ. The build-only parameters
+    /// (compression ratio, inclusion mode and sample size) are provenance and never conflict.
+    pub fn mismatches(&self, expected: &ReferenceSetMetadata) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let mut check = |name: &str, stored: String, wanted: String| {
+            if stored != wanted {
+                mismatches.push(format!("{name} is {stored}, expected {wanted}"));
+            }
+        };
+        check(
+            "max_dtw_dist",
+            self.max_dtw_dist.to_string(),
+            expected.max_dtw_dist.to_string(),
+        );
+        check(
+            "dtw_band",
+            self.dtw_band.to_string(),
+            expected.dtw_band.to_string(),
+        );
+        check(
+            "distance",
+            self.distance.name().to_string(),
+            expected.distance.name().to_string(),
+        );
+        check("metric", self.metric.name(), expected.metric.name());
+        // The origin is fitted to the data, only the kind of projection has to agree
+        check(
+            "projection",
+            self.projection.kind.name().to_string(),
+            expected.projection.kind.name().to_string(),
+        );
+        check("k", self.k.to_string(), expected.k.to_string());
+        check(
+            "spatial_filter",
+            self.spatial_filter.to_string(),
+            expected.spatial_filter.to_string(),
+        );
+        if self.spatial_filter && expected.spatial_filter {
+            check(
+                "error_point",
+                self.error_point.to_string(),
+                expected.error_point.to_string(),
+            );
+        }
+        mismatches
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.max_dtw_dist.to_le_bytes());
+        buffer.extend((self.dtw_band as u32).to_le_bytes());
+        buffer.extend((self.k as u32).to_le_bytes());
+        buffer.push(self.spatial_filter as u8);
+        buffer.push(self.distance.code());
+        buffer.push(self.metric.code());
+        let latitude = match self.metric {
+            PointMetric::Planar { latitude } => latitude,
+            _ => 0.0,
+        };
+        buffer.extend(latitude.to_le_bytes());
+        buffer.push(self.projection.kind.code());
+        buffer.extend(self.projection.origin.lat.to_le_bytes());
+        buffer.extend(self.projection.origin.lng.to_le_bytes());
+        buffer.extend(self.error_point.to_le_bytes());
+        buffer.extend(self.compression_ratio.to_le_bytes());
+        buffer.push(self.include_entire_trajectory as u8);
+        buffer.extend((self.sample_size as u32).to_le_bytes());
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<ReferenceSetMetadata> {
+        let max_dtw_dist = read_u32(reader)? as i32;
+        let dtw_band = read_u32(reader)? as usize;
+        let k = read_u32(reader)? as usize;
+        let spatial_filter = read_bool(reader)?;
+        let code = read_u8(reader)?;
+        let distance = DistanceKind::from_code(code)
+            .ok_or_else(|| invalid_data(format!("unknown distance {code}")))?;
+        let code = read_u8(reader)?;
+        let mut latitude = [0; 8];
+        reader.read_exact(&mut latitude)?;
+        let metric = PointMetric::from_code(code, f64::from_le_bytes(latitude))
+            .ok_or_else(|| invalid_data(format!("unknown point metric {code}")))?;
+        let code = read_u8(reader)?;
+        let projection = Projection {
+            kind: ProjectionKind::from_code(code)
+                .ok_or_else(|| invalid_data(format!("unknown projection {code}")))?,
+            origin: Point {
+                lat: read_u32(reader)? as i32,
+                lng: read_u32(reader)? as i32,
+                time: None,
+            },
+        };
+        Ok(ReferenceSetMetadata {
+            max_dtw_dist,
+            dtw_band,
+            distance,
+            metric,
+            projection,
+            k,
+            spatial_filter,
+            error_point: read_u32(reader)? as i32,
+            compression_ratio: read_u32(reader)? as i32,
+            include_entire_trajectory: read_bool(reader)?,
+            sample_size: read_u32(reader)? as usize,
+        })
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_bool(reader: &mut impl Read) -> io::Result<bool> {
+    Ok(read_u8(reader)? != 0)
+}
+
+/// Writes the header, metadata and trajectories of a reference set
+pub fn write_reference_set(
+    mut writer: impl Write,
+    metadata: &ReferenceSetMetadata,
+    reference_set: &[Vec<Point>],
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.extend(MAGIC);
+    buffer.extend(VERSION.to_le_bytes());
+    metadata.write(&mut buffer);
+    buffer.extend((reference_set.len() as u32).to_le_bytes());
+    writer.write_all(&buffer)?;
+    for t in reference_set {
+        buffer.clear();
+        write_points(&mut buffer, t);
+        buffer.push(is_timed(t) as u8);
+        if is_timed(t) {
+            write_times(&mut buffer, t);
+        }
+        writer.write_all(&buffer)?;
+    }
+    writer.flush()
+}
+
+pub fn read_reference_set(
+    mut reader: impl Read,
+) -> io::Result<(ReferenceSetMetadata, Vec<Vec<Point>>)> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data(String::from("not a reference set file")));
+    }
+    let version = read_u16(&mut reader)?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported reference set version {version}, expected {VERSION}"
+        )));
+    }
+    let metadata = ReferenceSetMetadata::read(&mut reader)?;
+    let len = read_u32(&mut reader)?;
+    let reference_set = (0..len)
+        .map(|_| {
+            let mut points = read_points(&mut reader)?;
+            if read_bool(&mut reader)? {
+                read_times(&mut reader, &mut points)?;
+            }
+            Ok(points)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok((metadata, reference_set))
+}
+
+pub fn save_reference_set(
+    path: impl AsRef<Path>,
+    metadata: &ReferenceSetMetadata,
+    reference_set: &[Vec<Point>],
+) -> io::Result<()> {
+    write_reference_set(BufWriter::new(File::create(path)?), metadata, reference_set)
+}
+
+/// Loads a reference set built under `expected`, projects it the way it was built and
+/// rebuilds its R-tree when the spatial filter is on
+pub fn load_reference_set(
+    path: impl AsRef<Path>,
+    expected: &ReferenceSetMetadata,
+) -> io::Result<LoadedReferenceSet> {
+    let (metadata, reference_set) = read_reference_set(BufReader::new(File::open(path)?))?;
+    let mismatches = metadata.mismatches(expected);
+    if !mismatches.is_empty() {
+        return Err(invalid_data(format!(
+            "reference set was built with incompatible parameters: {}",
+            mismatches.join(", ")
+        )));
+    }
+    let reference_set = metadata.projection.project_all(reference_set);
+    let metric = metadata.projection.kind.metric(expected.metric);
+    let r_tree = expected
+        .spatial_filter
+        .then(|| bulk_load_reference_set(&reference_set, metric));
+    Ok((reference_set, r_tree, metadata.projection))
+}
+
+/// Which entry a `ReferenceSet` over its budget evicts first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+pub enum EvictionPolicy {
+    /// The entry no encoded trajectory has referenced for the longest
+    #[default]
+    #[serde(rename = "lru")]
+    #[value(name = "lru")]
+    LeastRecentlyUsed,
+    /// The entry the fewest encoded trajectories have referenced
+    #[serde(rename = "lfu")]
+    #[value(name = "lfu")]
+    LeastFrequentlyUsed,
+    /// The entry added first
+    #[serde(rename = "oldest")]
+    Oldest,
+}
+
+impl EvictionPolicy {
+    /// Suffix of the mode name in the logs
+    pub fn suffix(self) -> &'static str {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed => "-LRU",
+            EvictionPolicy::LeastFrequentlyUsed => "-LFU",
+            EvictionPolicy::Oldest => "-OLDEST",
+        }
+    }
+}
+
+/// How an entry of a `ReferenceSet` has been used. Times are counted in recorded encodings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryUsage {
+    pub added: u64,
+    /// The last time a trajectory referenced the entry, or when it was added
+    pub last_used: u64,
+    /// The number of encoded trajectories that referenced the entry
+    pub uses: u64,
+}
+
+/// A reference set that grows as it is built, with the R-tree of its points when the
+/// spatial filter is on. With a budget, adding points beyond it evicts entries by the
+/// eviction policy, going by the usage `record` is told about, and the entries added
+/// since the last recorded encoding are evicted last. Evicting an entry moves the last
+/// one into its place, so ids are only stable while nothing is evicted.
+pub struct ReferenceSet {
+    trajectories: Vec<Vec<Point>>,
+    usage: Vec<EntryUsage>,
+    r_tree: Option<RTree<PointWithIndexReference>>,
+    metric: PointMetric,
+    budget: Option<usize>,
+    policy: EvictionPolicy,
+    points: usize,
+    /// The number of encodings recorded
+    clock: u64,
+    evicted: usize,
+}
+
+impl ReferenceSet {
+    /// An empty set holding at most `budget` points, without limit when None
+    pub fn new(
+        spatial_filter: bool,
+        metric: PointMetric,
+        budget: Option<usize>,
+        policy: EvictionPolicy,
+    ) -> ReferenceSet {
+        ReferenceSet {
+            trajectories: Vec::new(),
+            usage: Vec::new(),
+            r_tree: spatial_filter.then(RTree::new),
+            metric,
+            budget,
+            policy,
+            points: 0,
+            clock: 0,
+            evicted: 0,
+        }
+    }
+
+    pub fn trajectories(&self) -> &[Vec<Point>] {
+        &self.trajectories
+    }
+
+    pub fn r_tree(&self) -> Option<&RTree<PointWithIndexReference>> {
+        self.r_tree.as_ref()
+    }
+
+    /// The usage of every entry, by id
+    pub fn usage(&self) -> &[EntryUsage] {
+        &self.usage
+    }
+
+    pub fn len(&self) -> usize {
+        self.trajectories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trajectories.is_empty()
+    }
+
+    /// The number of points stored
+    pub fn points(&self) -> usize {
+        self.points
+    }
+
+    /// The number of entries evicted so far
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+
+    /// The ids of the entries the references of `encoded` were taken from, each once.
+    /// `encoded` has to be encoded against `trajectories`.
+    pub fn referenced_by(&self, encoded: &EncodedTrajectory) -> Vec<usize> {
+        encoded
+            .reference_uses(&self.trajectories)
+            .iter()
+            .map(|reference| reference.id)
+            .unique()
+            .collect()
+    }
+
+    /// Records that one more trajectory was encoded, referencing the entries with ids `entries`
+    pub fn record(&mut self, entries: &[usize]) {
+        self.clock += 1;
+        for &id in entries {
+            self.usage[id].uses += 1;
+            self.usage[id].last_used = self.clock;
+        }
+    }
+
+    /// Adds an entry, then evicts entries until the set is within its budget. An entry
+    /// that does not fit in the budget on its own is not added, and false is returned.
+    pub fn push(&mut self, trajectory: Vec<Point>) -> bool {
+        if self.budget.is_some_and(|budget| trajectory.len() > budget) {
+            return false;
+        }
+        self.points += trajectory.len();
+        self.trajectories.push(trajectory);
+        self.insert_points(self.len() - 1);
+        self.usage.push(EntryUsage {
+            added: self.clock,
+            last_used: self.clock,
+            uses: 0,
+        });
+        while self.budget.is_some_and(|budget| self.points > budget) {
+            let victim = (0..self.len())
+                .min_by_key(|&id| {
+                    let usage = &self.usage[id];
+                    let order = match self.policy {
+                        EvictionPolicy::LeastRecentlyUsed => (usage.last_used, usage.added),
+                        EvictionPolicy::LeastFrequentlyUsed => (usage.uses, usage.added),
+                        EvictionPolicy::Oldest => (usage.added, 0),
+                    };
+                    (usage.added == self.clock, order)
+                })
+                .expect("a set over its budget has entries");
+            self.evict(victim);
+        }
+        true
+    }
+
+    /// Removes an entry and its points in the R-tree, moving the last entry into its place
+    fn evict(&mut self, id: usize) {
+        let last = self.len() - 1;
+        self.remove_points(id);
+        if id != last {
+            self.remove_points(last);
+        }
+        let removed = self.trajectories.swap_remove(id);
+        self.usage.swap_remove(id);
+        if id != last {
+            self.insert_points(id);
+        }
+        self.points -= removed.len();
+        self.evicted += 1;
+    }
+
+    fn insert_points(&mut self, id: usize) {
+        if let Some(tree) = self.r_tree.as_mut() {
+            for (j, point) in self.trajectories[id].iter().enumerate() {
+                tree.insert(PointWithIndexReference {
+                    point: point.clone(),
+                    index: (id, j),
+                    metric: self.metric,
+                });
+            }
+        }
+    }
+
+    fn remove_points(&mut self, id: usize) {
+        if let Some(tree) = self.r_tree.as_mut() {
+            for (j, point) in self.trajectories[id].iter().enumerate() {
+                tree.remove(&PointWithIndexReference {
+                    point: point.clone(),
+                    index: (id, j),
+                    metric: self.metric,
+                });
+            }
+        }
+    }
+
+    /// The entries and their R-tree
+    pub fn into_parts(self) -> (Vec<Vec<Point>>, Option<RTree<PointWithIndexReference>>) {
+        (self.trajectories, self.r_tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::Mode;
+    use crate::builder::{BuilderKind, SimilarityKind};
+    use crate::sampling::Sampling;
+
+    #[test]
+    fn test_reference_set_roundtrip_and_mismatch() {
+        let rest_conf = RestMode {
+            rs: 100,
+            compression_ratio: 3,
+            spatial_filter: true,
+            include_entire_trajectory: true,
+            k: 3,
+            error_point: 70,
+            budget: None,
+            eviction: EvictionPolicy::default(),
+            builder: BuilderKind::Greedy,
+            set_size: None,
+            similarity: SimilarityKind::Distance,
+        };
+        let conf = Config {
+            n: 1000,
+            max_dtw_dist: 200,
+            dtw_band: 0,
+            distance: DistanceKind::MaxDtw,
+            metric: PointMetric::Planar { latitude: 41.15 },
+            projection: ProjectionKind::None,
+            sampling: Sampling::Head,
+            seed: 0,
+            mode: Mode::Rest(rest_conf),
+        };
+        let projection = Projection {
+            kind: ProjectionKind::Utm,
+            origin: Point {
+                lat: 41_150_000,
+                lng: -8_610_000,
+                time: None,
+            },
+        };
+        let metadata = ReferenceSetMetadata::new(&conf, &rest_conf, 100, projection);
+        let reference_set = vec![
+            vec![
+                Point {
+                    lat: 41_150_000,
+                    lng: -8_610_000,
+                    time: None,
+                },
+                Point {
+                    lat: 41_151_000,
+                    lng: -8_610_000,
+                    time: None,
+                },
+            ],
+            vec![Point {
+                lat: 41_160_000,
+                lng: -8_620_000,
+                time: None,
+            }],
+        ];
+
+        let mut bytes = Vec::new();
+        write_reference_set(&mut bytes, &metadata, &reference_set).unwrap();
+        let (read_metadata, read_set) = read_reference_set(bytes.as_slice()).unwrap();
+        assert_eq!(read_metadata, metadata);
+        assert_eq!(read_set, reference_set);
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 2;
+        assert!(read_reference_set(wrong_version.as_slice()).is_err());
+
+        let other = ReferenceSetMetadata {
+            max_dtw_dist: 100,
+            sample_size: 500,
+            projection: Projection {
+                kind: ProjectionKind::Utm,
+                origin: Point {
+                    lat: 0,
+                    lng: 0,
+                    time: None,
+                },
+            },
+            ..metadata.clone()
+        };
+        assert_eq!(
+            metadata.mismatches(&other),
+            vec![String::from("max_dtw_dist is 200, expected 100")]
+        );
+        let frechet = ReferenceSetMetadata {
+            distance: DistanceKind::Frechet,
+            ..metadata.clone()
+        };
+        assert_eq!(
+            metadata.mismatches(&frechet),
+            vec![String::from("distance is max_dtw, expected frechet")]
+        );
+        assert_eq!(
+            bulk_load_reference_set(&read_set, metadata.metric).size(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_reference_set_evicts_within_budget() {
+        let metric = PointMetric::Haversine;
+        let line = |lat: i32| {
+            (0..4)
+                .map(|i| Point {
+                    lat: lat + i * 1000,
+                    lng: -8_610_000,
+                    time: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let evictions = |policy| {
+            let mut set = ReferenceSet::new(true, metric, Some(8), policy);
+            set.push(line(41_100_000));
+            set.record(&[]);
+            set.push(line(41_200_000));
+            // The second entry is used twice, the first once and last
+            set.record(&[1]);
+            set.record(&[1]);
+            set.record(&[0]);
+            assert!(!set.push([line(41_300_000), line(41_400_000), line(41_500_000)].concat()));
+            assert!(set.push(line(41_300_000)));
+            assert_eq!((set.len(), set.points(), set.evicted()), (2, 8, 1));
+            // The R-tree indexes exactly the points left, under their new ids
+            let tree = set.r_tree().unwrap();
+            assert_eq!(tree.size(), set.points());
+            assert!(tree
+                .iter()
+                .all(|p| set.trajectories()[p.index.0][p.index.1] == p.point));
+            set.trajectories().iter().map(|t| t[0].lat).collect_vec()
+        };
+        assert_eq!(
+            evictions(EvictionPolicy::LeastRecentlyUsed),
+            vec![41_100_000, 41_300_000]
+        );
+        assert_eq!(
+            evictions(EvictionPolicy::LeastFrequentlyUsed),
+            vec![41_300_000, 41_200_000]
+        );
+        assert_eq!(
+            evictions(EvictionPolicy::Oldest),
+            vec![41_300_000, 41_200_000]
+        );
+    }
+}
@@ -1,18 +1,25 @@
-use crate::dtw_band::{dtw as dtw_normal, dtw_band};
-use crate::max_dtw::{max_dtw as og_dtw, max_dtw_band};
+use crate::container::locate;
+use crate::distance::{DistanceState, TrajectoryDistance};
+use crate::metric::PointMetric;
 use crate::spatial_filter::{PointWithIndexReference, SpatialQuery};
+use crate::temporal::TimeMapping;
 use haversine::{distance, Location};
 use itertools::Itertools;
 use rstar::RTree;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+
+pub use crate::max_dtw::max_dtw;
+use std::ops::Range;
 
 extern crate haversine;
 
+/// Converts a `(lat, lng)` pair in degrees. Inputs in other axis orders go through `source::CoordinateOrder`.
 impl From<(f32, f32)> for Point {
     fn from(value: (f32, f32)) -> Point {
         Point {
             lat: (value.0 * 1000000.0) as i32,
             lng: (value.1 * 1000000.0) as i32,
+            time: None,
         }
     }
 }
@@ -20,6 +27,8 @@ impl From<(f32, f32)> for Point {
 pub struct Point {
     pub lat: i32,
     pub lng: i32,
+    /// Seconds since the Unix epoch, when the dataset records when the point was sampled
+    pub time: Option<i64>,
 }
 impl Point {
     pub fn distance(&self, other: &Point) -> f64 {
@@ -53,28 +62,118 @@ impl Point {
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum SubTrajectory<'a> {
     Trajectory(Vec<Point>),
-    Reference(&'a [Point]),
+    /// A reference slice together with the number of source points it stands in for,
+    /// and how its times map onto them when the source has times
+    Reference(&'a [Point], usize, Option<TimeMapping>),
 }
 #[derive(Clone)]
 pub struct EncodedTrajectory<'a>(pub Vec<SubTrajectory<'a>>);
 
-pub fn max_dtw<'a>(
-    st: &'a [Point],
-    rt: &'a [Point],
-    memo: &mut HashMap<(&'a [Point], &'a [Point]), f64>,
-    band: usize,
-) -> f64 {
-    if band == 0 {
-        return og_dtw(st, rt, memo, None);
+/// Where a piece of a decoded trajectory came from. `decoded` indexes into the
+/// reconstructed points and `source` into the trajectory that was encoded, so
+/// `max_dtw(&original[source], &decoded[decoded])` is the error of the piece.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DecodedSpan {
+    pub decoded: Range<usize>,
+    pub source: Range<usize>,
+    pub is_reference: bool,
+}
+
+/// The span of a reference trajectory a reference piece used, and the number of source
+/// points it covered. `start..=end` is inclusive, as in `container::StoredSubTrajectory`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReferenceUse {
+    pub id: usize,
+    pub start: usize,
+    pub end: usize,
+    pub covered: usize,
+}
+
+impl EncodedTrajectory<'_> {
+    pub fn decode(&self) -> (Vec<Point>, Vec<DecodedSpan>) {
+        decode(self)
+    }
+
+    /// The reference pieces in order, resolved against the reference set the trajectory
+    /// was encoded with. Pieces whose slice does not lie in the set are left out.
+    pub fn reference_uses(&self, reference_set: &[impl AsRef<[Point]>]) -> Vec<ReferenceUse> {
+        self.0
+            .iter()
+            .filter_map(|st| match st {
+                SubTrajectory::Reference(slice, covered, _) => {
+                    let (id, start) = locate(slice, reference_set)?;
+                    Some(ReferenceUse {
+                        id,
+                        start,
+                        end: start + slice.len() - 1,
+                        covered: *covered,
+                    })
+                }
+                SubTrajectory::Trajectory(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Reconstructs the polyline of an encoding with the source range of each piece.
+/// A raw piece ends on the point the next piece starts from, while a reference piece
+/// covers its `covered` points only, so the source ranges of consecutive spans share
+/// a point after a raw piece and none after a reference piece. The last span of an
+/// encoding made by `encode` ends at the length of the source: no point is dropped.
+pub fn decode(encoded: &EncodedTrajectory) -> (Vec<Point>, Vec<DecodedSpan>) {
+    let mut points: Vec<Point> = Vec::new();
+    let mut spans = Vec::new();
+    let mut source_index = 0;
+    let mut after_raw = false;
+
+    for st in &encoded.0 {
+        match st {
+            SubTrajectory::Trajectory(raw) => {
+                let start = append_raw(&mut points, raw, after_raw);
+                spans.push(DecodedSpan {
+                    decoded: start..points.len(),
+                    source: source_index..source_index + raw.len(),
+                    is_reference: false,
+                });
+                source_index += raw.len().saturating_sub(1);
+                after_raw = true;
+            }
+            SubTrajectory::Reference(reference, covered, mapping) => {
+                // A reference only approximates the junction point, so the raw point before it is kept
+                let start = points.len();
+                match mapping {
+                    Some(mapping) => points.extend(mapping.apply(reference)),
+                    None => points.extend(reference.iter().cloned()),
+                }
+                spans.push(DecodedSpan {
+                    decoded: start..points.len(),
+                    source: source_index..source_index + covered,
+                    is_reference: true,
+                });
+                source_index += covered;
+                after_raw = false;
+            }
+        }
     }
-    max_dtw_band(st, rt, memo, band)
+
+    (points, spans)
+}
+
+/// Appends a raw segment to a decoded polyline and returns where the segment starts.
+/// A raw segment ends on the point the next piece starts from, so two consecutive
+/// raw segments share their junction point.
+pub fn append_raw(points: &mut Vec<Point>, raw: &[Point], after_raw: bool) -> usize {
+    let shared = usize::from(after_raw && !raw.is_empty());
+    let start = points.len() - shared;
+    points.extend(raw[shared..].iter().cloned());
+    start
 }
 
 pub fn encode<'a>(
     reference_trajectories: &'a [&[Point]],
     trajectory: &[Point],
     spatial_deviation: f64,
-    band: usize,
+    distance: &dyn TrajectoryDistance,
     k: usize,
     r_tree: Option<&RTree<PointWithIndexReference>>,
     spatial_filter_distance: f64,
@@ -86,31 +185,30 @@ pub fn encode<'a>(
     let mut direct_points: u64 = 0;
 
     while last_indexed_point < length - 1 {
-        let candidate_vector = match r_tree {
-            Some(tree) => tree
-                .points_within_envelope(
-                    spatial_filter_distance,
-                    trajectory[last_indexed_point].clone(),
-                )
-                .iter()
-                .map(|PointWithIndexReference { index: (i, j), .. }| {
-                    &reference_trajectories[*i][*j..]
-                })
-                .collect_vec(),
-            None => reference_trajectories.to_vec(),
-        };
+        let candidate_vector = candidate_references(
+            reference_trajectories,
+            r_tree,
+            spatial_filter_distance,
+            &trajectory[last_indexed_point],
+            distance.metric(),
+        );
 
         //spatial deviation from m to k
         match greedy_mrt_expand(
             &trajectory[last_indexed_point..],
             candidate_vector.as_slice(),
             spatial_deviation / 1000.0,
-            band,
+            distance,
             k,
         ) {
             Some((new_last_index, mrt)) => {
+                let mapping = TimeMapping::fit(
+                    &trajectory[last_indexed_point..last_indexed_point + new_last_index],
+                );
                 last_indexed_point += new_last_index;
-                encoded_trajectory.0.push(SubTrajectory::Reference(mrt));
+                encoded_trajectory
+                    .0
+                    .push(SubTrajectory::Reference(mrt, new_last_index, mapping));
                 references += 1;
             }
             None => {
@@ -127,6 +225,19 @@ pub fn encode<'a>(
         // this originally counts edges not points, and points = edges + 1
         direct_points += 1;
     }
+    // A reference piece shares no point with the next one, so the point its match could
+    // not reach is kept raw when it is the last one
+    if last_indexed_point == length - 1
+        && !matches!(
+            encoded_trajectory.0.last(),
+            Some(SubTrajectory::Trajectory(_))
+        )
+    {
+        encoded_trajectory.0.push(SubTrajectory::Trajectory(vec![
+            trajectory[length - 1].clone()
+        ]));
+        direct_points += 1;
+    }
 
     (
         encoded_trajectory,
@@ -134,74 +245,656 @@ pub fn encode<'a>(
     )
 }
 
+/// The reference slices a match starting at `start` is looked for in: the suffixes
+/// starting near it with the spatial filter, every reference trajectory otherwise
+fn candidate_references<'a>(
+    reference_trajectories: &[&'a [Point]],
+    r_tree: Option<&RTree<PointWithIndexReference>>,
+    spatial_filter_distance: f64,
+    start: &Point,
+    metric: PointMetric,
+) -> Vec<&'a [Point]> {
+    match r_tree {
+        Some(tree) => tree
+            .points_within_envelope(spatial_filter_distance, start.clone(), metric)
+            .iter()
+            .map(|PointWithIndexReference { index: (i, j), .. }| &reference_trajectories[*i][*j..])
+            .collect_vec(),
+        None => reference_trajectories.to_vec(),
+    }
+}
+
 fn greedy_mrt_expand<'a>(
     trajectory: &[Point],
     reference_trajectories: &[&'a [Point]],
     max_deviation: f64,
-    dtw_band: usize,
+    distance: &dyn TrajectoryDistance,
     k: usize,
 ) -> Option<(usize, &'a [Point])> {
     let mut subtraj_mrt_map = HashMap::new();
 
     for reference_trajectory in reference_trajectories {
-        let mut memo = HashMap::new();
-        let mut current_mrts: HashSet<(usize, usize)> = (0..reference_trajectory.len() - 1)
-            .into_iter()
-            .filter(|&j| {
-                max_dtw(
-                    &trajectory[0..=1],
-                    &reference_trajectory[j..=j + 1],
-                    &mut memo,
-                    dtw_band,
-                ) < max_deviation
-            })
-            .map(|j| (j, j + 1))
-            .collect();
+        let new_state = |trajectory_end: usize, start: usize, end: usize| {
+            new_match_state(
+                trajectory,
+                reference_trajectory,
+                (trajectory_end, start, end),
+                max_deviation,
+                distance,
+            )
+        };
+        // Live matches by their range on the reference, the first one taken as the best
+        let mut current_mrts = first_matches(
+            reference_trajectory.len(),
+            |s, e| new_state(1, s, e),
+            max_deviation,
+        );
 
         let mut trajectory_index = 1;
-        while !current_mrts.is_empty() {
+        while let Some(&((best_start, best_end), _)) = current_mrts.first() {
             trajectory_index += 1;
-            current_mrts.iter().next().map(|arbitrary_match| {
+            subtraj_mrt_map
+                .entry(trajectory_index)
+                .or_insert_with(|| &reference_trajectory[best_start..=best_end]);
+            if trajectory_index >= trajectory.len() {
+                break;
+            }
+            current_mrts = advance_matches(
+                current_mrts,
+                trajectory,
+                reference_trajectory,
+                |s, e| new_state(trajectory_index, s, e),
+                max_deviation,
+                k,
+            );
+        }
+    }
+
+    subtraj_mrt_map.into_iter().max_by_key(|&(k, _)| k)
+}
+
+/// A match by its range on a reference slice, with its state between the trajectory and
+/// the reference slice from the start of the range on
+pub type LiveMatch = ((usize, usize), Box<dyn DistanceState>);
+
+/// The matches of the first two trajectory points with every edge of a reference slice
+/// of `reference_len` points, in the order of the edges. `new_state(start, end)` builds
+/// their states.
+fn first_matches(
+    reference_len: usize,
+    mut new_state: impl FnMut(usize, usize) -> Option<Box<dyn DistanceState>>,
+    max_deviation: f64,
+) -> Vec<LiveMatch> {
+    (0..reference_len - 1)
+        .filter_map(|j| Some(((j, j + 1), new_state(j, j + 1)?)))
+        .filter(|(_, state)| state.distance() < max_deviation)
+        .collect()
+}
+
+/// Advances every match by one trajectory point, with and without one more reference
+/// point, and keeps the best of them and of the matches restarting at their ends as
+/// `keep_best_matches` does. The matches are between the start of `trajectory` and
+/// `reference_trajectory`, and `new_state(start, end)` builds the state of a match no
+/// match advanced into.
+fn advance_matches(
+    matches: Vec<LiveMatch>,
+    trajectory: &[Point],
+    reference_trajectory: &[Point],
+    mut new_state: impl FnMut(usize, usize) -> Option<Box<dyn DistanceState>>,
+    max_deviation: f64,
+    k: usize,
+) -> Vec<LiveMatch> {
+    // None marks a match that neither it nor any extension of it can meet the deviation
+    let mut states = HashMap::new();
+    let mut children = Vec::new();
+    for ((rt_start, rt_end), mut same_end) in matches {
+        if rt_end >= reference_trajectory.len() - 1 {
+            continue;
+        }
+        let reference = &reference_trajectory[rt_start..];
+        same_end.push_a(trajectory, reference);
+        if same_end.extension_bound() >= max_deviation {
+            states.entry((rt_start, rt_end)).or_insert(None);
+            states.entry((rt_start, rt_end + 1)).or_insert(None);
+        } else {
+            let mut extended = same_end.clone();
+            extended.push_b(trajectory, reference);
+            states.entry((rt_start, rt_end)).or_insert(Some(same_end));
+            states
+                .entry((rt_start, rt_end + 1))
+                .or_insert(Some(extended));
+        }
+        children.extend(match_children(rt_start, rt_end));
+    }
+    // A match restarting at the end of another is only built when no match advanced into it
+    let children = keep_best_matches(
+        children.into_iter().filter_map(|(s, e)| {
+            let state = states
+                .entry((s, e))
+                .or_insert_with(|| new_state(s, e))
+                .as_ref()?;
+            Some((state.distance(), (s, e)))
+        }),
+        max_deviation,
+        k,
+    );
+    children
+        .into_iter()
+        .map(|key| {
+            let state = states.remove(&key).flatten();
+            (key, state.expect("every child kept has a live state"))
+        })
+        .collect()
+}
+
+/// The state of a match from the start of `trajectory` to `reference_trajectory[start..]`,
+/// with the first `trajectory_end + 1` and `end - start + 1` points pushed.
+/// None when a lower bound already exceeds `max_deviation`.
+fn new_match_state(
+    trajectory: &[Point],
+    reference_trajectory: &[Point],
+    (trajectory_end, start, end): (usize, usize, usize),
+    max_deviation: f64,
+    distance: &dyn TrajectoryDistance,
+) -> Option<Box<dyn DistanceState>> {
+    if distance.lower_bound_reaches(
+        &trajectory[..=trajectory_end],
+        &reference_trajectory[start..=end],
+        max_deviation,
+    ) {
+        return None;
+    }
+    let reference = &reference_trajectory[start..];
+    let mut state = distance.state(trajectory, reference);
+    for _ in start..=end {
+        state.push_b(trajectory, reference);
+    }
+    for _ in 0..=trajectory_end {
+        state.push_a(trajectory, reference);
+        if state.push_a_bound() >= max_deviation {
+            return None;
+        }
+    }
+    Some(state)
+}
+
+/// The matches a match over `start..=end` of a reference grows into with one more
+/// trajectory point. On equal distances, advancing on both sides is preferred over
+/// staying on the reference.
+fn match_children(start: usize, end: usize) -> [(usize, usize); 3] {
+    [(start, end + 1), (start, end), (end, end + 1)]
+}
+
+/// The ranges of the `k` closest matches within `max_deviation`, all of them when `k` is 0,
+/// closest first. Distances are compared in whole meters, keeping the order of ties, and a
+/// range among the `k` twice is kept once.
+fn keep_best_matches(
+    scored: impl Iterator<Item = (f64, (usize, usize))>,
+    max_deviation: f64,
+    k: usize,
+) -> Vec<(usize, usize)> {
+    scored
+        .filter(|(dist, _)| *dist < max_deviation)
+        .sorted_by_key(|(dist, _)| (*dist * 1000.0) as i32)
+        .take(if k != 0 { k } else { usize::MAX })
+        .map(|(_, key)| key)
+        .unique()
+        .collect_vec()
+}
+
+/// A reference slice and its live matches
+pub type Candidate<'a> = (&'a [Point], Vec<LiveMatch>);
+
+/// Encodes a trajectory whose points arrive one at a time, keeping the live matches of
+/// `greedy_mrt_expand` between points and emitting every piece as soon as it is final,
+/// on the point no live match reaches. Without `max_latency` the pieces are the ones
+/// `encode` makes of the whole trajectory.
+///
+/// Match states keep no reference to the buffered points, which move as the buffer grows,
+/// and are handed the buffer to advance over the next point. `max_latency` keeps the
+/// buffer short: once it holds that many points, the best match so far is emitted as if
+/// the trajectory ended there, so every point is emitted within `max_latency` pushes of
+/// its arrival.
+pub struct StreamingEncoder<'a> {
+    reference_trajectories: &'a [&'a [Point]],
+    distance: &'a dyn TrajectoryDistance,
+    /// In meters, like in `encode`
+    spatial_deviation: f64,
+    k: usize,
+    r_tree: Option<&'a RTree<PointWithIndexReference>>,
+    spatial_filter_distance: f64,
+    max_latency: Option<usize>,
+    /// The points from the start of the current piece on
+    buffer: Vec<Point>,
+    /// The reference slices the current piece may match, with their matches over the
+    /// whole buffer
+    candidates: Vec<Candidate<'a>>,
+    /// The piece the current one ends as if the trajectory ended now: the number of
+    /// points it covers and its reference slice
+    best: Option<(usize, &'a [Point])>,
+    /// Whether the last piece emitted is raw, and so ends on the first buffered point
+    last_raw: bool,
+}
+
+impl<'a> StreamingEncoder<'a> {
+    /// Takes the parameters of `encode`, and a number of points after which a piece is
+    /// emitted even though it could grow further. Latencies below 2 are raised to 2.
+    pub fn new(
+        reference_trajectories: &'a [&'a [Point]],
+        spatial_deviation: f64,
+        distance: &'a dyn TrajectoryDistance,
+        k: usize,
+        r_tree: Option<&'a RTree<PointWithIndexReference>>,
+        spatial_filter_distance: f64,
+        max_latency: Option<usize>,
+    ) -> StreamingEncoder<'a> {
+        StreamingEncoder {
+            reference_trajectories,
+            distance,
+            spatial_deviation,
+            k,
+            r_tree,
+            spatial_filter_distance,
+            max_latency: max_latency.map(|n| n.max(2)),
+            buffer: Vec::new(),
+            candidates: Vec::new(),
+            best: None,
+            last_raw: false,
+        }
+    }
+
+    /// Takes the next point and returns the pieces that became final
+    pub fn push(&mut self, point: Point) -> Vec<SubTrajectory<'a>> {
+        let mut emitted = Vec::new();
+        let mut pending = vec![point];
+        while let Some(point) = pending.pop() {
+            self.buffer.push(point);
+            if self.step() {
+                emitted.push(self.emit());
+                // The points after the piece start the next one
+                pending.extend(self.buffer.drain(..).rev());
+            }
+        }
+        emitted
+    }
+
+    /// Ends the trajectory, returning its last pieces, and readies the encoder for the next one.
+    /// These are the best pending piece, which covers the whole buffer, or the last point
+    /// alone when a reference piece could not reach it, as `encode` keeps it.
+    pub fn finish(&mut self) -> Vec<SubTrajectory<'a>> {
+        let mut pieces = Vec::new();
+        while self.buffer.len() >= 2 {
+            pieces.push(self.emit());
+        }
+        if !self.last_raw && !self.buffer.is_empty() {
+            pieces.push(SubTrajectory::Trajectory(std::mem::take(&mut self.buffer)));
+        }
+        self.buffer.clear();
+        self.best = None;
+        self.last_raw = false;
+        pieces
+    }
+
+    /// Advances the live matches over the last point pushed, as `greedy_mrt_expand` does
+    /// when that point is not the last one. Returns whether a piece is final, because no
+    /// match reaches the point or the buffer is full.
+    fn step(&mut self) -> bool {
+        let last = self.buffer.len() - 1;
+        let max_deviation = self.spatial_deviation / 1000.0;
+        if last == 0 {
+            self.candidates = candidate_references(
+                self.reference_trajectories,
+                self.r_tree,
+                self.spatial_filter_distance,
+                &self.buffer[0],
+                self.distance.metric(),
+            )
+            .into_iter()
+            .map(|reference| (reference, Vec::new()))
+            .collect();
+            return false;
+        }
+
+        // The best match over the points before this one
+        let previous = self.best_match();
+        let (buffer, distance, k) = (&self.buffer, self.distance, self.k);
+        for (reference, live) in self.candidates.iter_mut() {
+            let reference = *reference;
+            let new_state = |trajectory_end: usize, start: usize, end: usize| {
+                new_match_state(
+                    buffer,
+                    reference,
+                    (trajectory_end, start, end),
+                    max_deviation,
+                    distance,
+                )
+            };
+            *live = if last == 1 {
+                first_matches(reference.len(), |s, e| new_state(1, s, e), max_deviation)
+            } else {
+                advance_matches(
+                    std::mem::take(live),
+                    buffer,
+                    reference,
+                    |s, e| new_state(last, s, e),
+                    max_deviation,
+                    k,
+                )
+            };
+        }
+
+        match self.best_match() {
+            Some(latest) => {
+                // Ending on this point, the piece covers the whole buffer
+                self.best = Some((last + 1, latest));
+                self.max_latency.is_some_and(|n| self.buffer.len() >= n)
+            }
+            // No match can grow any more, so the piece stops before this point
+            None => {
+                self.best = previous.map(|previous| (last, previous));
+                true
+            }
+        }
+    }
+
+    /// The reference slice of the best live match
+    fn best_match(&self) -> Option<&'a [Point]> {
+        self.candidates.iter().find_map(|&(reference, ref live)| {
+            let &((start, end), _) = live.first()?;
+            Some(&reference[start..=end])
+        })
+    }
+
+    /// The piece of the best match, or a raw edge when there is none, leaving the points
+    /// after it in the buffer
+    fn emit(&mut self) -> SubTrajectory<'a> {
+        let (piece, covered) = match self.best.take() {
+            Some((covered, reference)) => (
+                SubTrajectory::Reference(
+                    reference,
+                    covered,
+                    TimeMapping::fit(&self.buffer[..covered]),
+                ),
+                covered,
+            ),
+            None => (SubTrajectory::Trajectory(self.buffer[..=1].to_vec()), 1),
+        };
+        self.buffer.drain(..covered);
+        self.candidates.clear();
+        self.last_raw = matches!(piece, SubTrajectory::Trajectory(_));
+        piece
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::{Hausdorff, MaxDtw};
+    use crate::source::tests::sample_points;
+    use crate::spatial_filter::bulk_load_reference_set;
+    use crate::temporal::Sed;
+
+    fn line(lat: i32, lng: i32, n: i32) -> Vec<Point> {
+        (0..n)
+            .map(|i| Point {
+                lat: lat + i * 1000,
+                lng,
+                time: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_stitches_raw_and_reference_pieces() {
+        let reference = line(41_150_000, -8_610_000, 10);
+        // Starts away from the reference, follows it, then leaves it again
+        let mut leaving = line(41_140_000, -8_630_000, 3);
+        leaving.extend(line(41_151_000, -8_610_020, 6));
+        leaving.extend(line(41_170_000, -8_630_000, 3));
+        // Follows the reference point for point and ends inside it
+        let inside = line(41_152_000, -8_610_020, 5);
+        let reference_set = [reference.as_slice()];
+        let encode_line = |trajectory: &[Point]| {
+            encode(
+                &reference_set,
+                trajectory,
+                200.0,
+                &MaxDtw {
+                    band: 0,
+                    metric: PointMetric::Haversine,
+                },
+                0,
+                None,
+                0.0,
+            )
+        };
+
+        let (encoded, _) = encode_line(&leaving);
+        assert_eq!(encoded.decode().0[0], leaving[0]);
+        let (encoded, _) = encode_line(&inside);
+        assert_eq!(encoded.decode().0.len(), inside.len());
+
+        for trajectory in [&leaving, &inside] {
+            let (encoded, shape) = encode_line(trajectory);
+            let (decoded, spans) = encoded.decode();
+
+            assert!(spans.iter().any(|span| span.is_reference));
+            assert_eq!(spans.len() as u64, shape.1 + shape.2.saturating_sub(1));
+            assert_eq!(spans[0].source.start, 0);
+            assert_eq!(spans.last().unwrap().source.end, trajectory.len());
+            for (span, next) in spans.iter().tuple_windows() {
+                assert!(span.source.end - 1 <= next.source.start);
+                assert!(next.source.start <= span.source.end);
+            }
+            for span in spans {
+                let error = max_dtw(
+                    &trajectory[span.source.clone()],
+                    &decoded[span.decoded.clone()],
+                    0,
+                    PointMetric::Haversine,
+                );
+                assert!(error < 0.2, "{:?} deviates by {} km", span, error);
+            }
+        }
+    }
+
+    /// `greedy_mrt_expand` as it was before match states, recomputing the distance of
+    /// every match from scratch on each trajectory point
+    fn greedy_from_scratch<'a>(
+        trajectory: &[Point],
+        reference_trajectories: &[&'a [Point]],
+        max_deviation: f64,
+        k: usize,
+    ) -> Option<(usize, &'a [Point])> {
+        let max_dtw = |end: usize, reference: &[Point]| {
+            max_dtw(&trajectory[..=end], reference, 0, PointMetric::Haversine)
+        };
+        let mut subtraj_mrt_map = HashMap::new();
+        for reference_trajectory in reference_trajectories {
+            let mut current_mrts = (0..reference_trajectory.len() - 1)
+                .filter(|&j| max_dtw(1, &reference_trajectory[j..=j + 1]) < max_deviation)
+                .map(|j| (j, j + 1))
+                .collect_vec();
+            let mut trajectory_index = 1;
+            while let Some(&(best_start, best_end)) = current_mrts.first() {
+                trajectory_index += 1;
                 subtraj_mrt_map
                     .entry(trajectory_index)
-                    .or_insert_with(|| &reference_trajectory[arbitrary_match.0..=arbitrary_match.1])
-            });
-            current_mrts = current_mrts
-                .iter()
-                .cloned()
-                .filter(|&(_, rt_end)| {
-                    (trajectory_index < trajectory.len() - 1)
-                        && (rt_end < reference_trajectory.len() - 1)
-                })
-                .flat_map(|(rt_start, rt_end)| {
-                    [
-                        (rt_start, rt_end),
-                        (rt_end, rt_end + 1),
-                        (rt_start, rt_end + 1),
-                    ]
+                    .or_insert_with(|| &reference_trajectory[best_start..=best_end]);
+                if trajectory_index >= trajectory.len() {
+                    break;
+                }
+                current_mrts = current_mrts
                     .iter()
-                    .cloned()
+                    .filter(|&&(_, rt_end)| rt_end < reference_trajectory.len() - 1)
+                    .flat_map(|&(rt_start, rt_end)| match_children(rt_start, rt_end))
                     .map(|(s, e)| {
-                        (
-                            max_dtw(
-                                &trajectory[..=trajectory_index],
-                                &reference_trajectory[s..=e],
-                                &mut memo,
-                                dtw_band,
-                            ),
-                            s,
-                            e,
-                        )
+                        let dist = max_dtw(trajectory_index, &reference_trajectory[s..=e]);
+                        (dist, (s, e))
                     })
-                    .filter(|(dist, _, _)| *dist < max_deviation)
-                    .collect_vec()
-                })
-                .sorted_by_key(|(dist, _, _)| (*dist * 1000.0) as i32)
-                .take(if k != 0 { k } else { usize::MAX })
-                .map(|(_, s, e)| (s, e))
-                .collect();
+                    .filter(|(dist, _)| *dist < max_deviation)
+                    .sorted_by_key(|(dist, _)| (*dist * 1000.0) as i32)
+                    .take(if k != 0 { k } else { usize::MAX })
+                    .map(|(_, key)| key)
+                    .unique()
+                    .collect_vec();
+            }
         }
+        subtraj_mrt_map.into_iter().max_by_key(|&(k, _)| k)
     }
 
-    subtraj_mrt_map.into_iter().max_by_key(|&(k, _)| k)
+    #[test]
+    fn test_greedy_keeps_the_matches_it_kept_before_match_states() {
+        let trajectories = sample_points();
+        let (references, trajectories) = trajectories.split_at(10);
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        let distance = MaxDtw {
+            band: 0,
+            metric: PointMetric::Haversine,
+        };
+
+        for k in [1, 3, 0] {
+            for trajectory in &trajectories[..5] {
+                for start in (0..trajectory.len() - 1).step_by(4) {
+                    let suffix = &trajectory[start..];
+                    let expected = greedy_from_scratch(suffix, &reference_set, 0.2, k);
+                    assert_eq!(
+                        greedy_mrt_expand(suffix, &reference_set, 0.2, &distance, k),
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_is_reused_across_finishes() {
+        let trajectories = sample_points();
+        let (references, trajectories) = trajectories.split_at(10);
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        let r_tree = bulk_load_reference_set(references, PointMetric::Haversine);
+        let metric = PointMetric::Haversine;
+        let distances: [&dyn TrajectoryDistance; 3] = [
+            &MaxDtw { band: 0, metric },
+            &Hausdorff { metric },
+            &Sed { metric },
+        ];
+
+        for distance in distances {
+            let mut encoder = StreamingEncoder::new(
+                &reference_set,
+                200.0,
+                distance,
+                3,
+                Some(&r_tree),
+                70.0,
+                None,
+            );
+            // Every trajectory is cut in two, finishing the encoder between the parts
+            for (i, trajectory) in trajectories.iter().enumerate() {
+                let cut = trajectory.len() * (i % 3 + 1) / 4;
+                for part in [&trajectory[..cut], &trajectory[cut..]] {
+                    if part.is_empty() {
+                        continue;
+                    }
+                    let mut streamed = Vec::new();
+                    for p in part {
+                        streamed.extend(encoder.push(p.clone()));
+                    }
+                    streamed.extend(encoder.finish());
+                    let (encoded, _) = encode(
+                        &reference_set,
+                        part,
+                        200.0,
+                        distance,
+                        3,
+                        Some(&r_tree),
+                        70.0,
+                    );
+                    assert!(streamed == encoded.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_matches_encode() {
+        let trajectories = sample_points();
+        let (references, trajectories) = trajectories.split_at(10);
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        let distance = MaxDtw {
+            band: 0,
+            metric: PointMetric::Haversine,
+        };
+        let r_tree = bulk_load_reference_set(references, PointMetric::Haversine);
+
+        for r_tree in [None, Some(&r_tree)] {
+            let mut encoder =
+                StreamingEncoder::new(&reference_set, 200.0, &distance, 3, r_tree, 70.0, None);
+            for trajectory in trajectories {
+                let (encoded, _) = encode(
+                    &reference_set,
+                    trajectory,
+                    200.0,
+                    &distance,
+                    3,
+                    r_tree,
+                    70.0,
+                );
+                let mut streamed = Vec::new();
+                let mut start = 0;
+                for (i, p) in trajectory.iter().enumerate() {
+                    for piece in encoder.push(p.clone()) {
+                        // Emitted on the point that ends it, which starts the next piece:
+                        // the second point of a raw edge, or the point no match reaches
+                        start += match piece {
+                            SubTrajectory::Reference(_, covered, _) => covered,
+                            SubTrajectory::Trajectory(_) => 1,
+                        };
+                        assert_eq!(start, i);
+                        streamed.push(piece);
+                    }
+                }
+                streamed.extend(encoder.finish());
+                assert!(streamed == encoded.0);
+            }
+        }
+
+        let max_latency = 5;
+        let mut encoder = StreamingEncoder::new(
+            &reference_set,
+            200.0,
+            &distance,
+            3,
+            Some(&r_tree),
+            70.0,
+            Some(max_latency),
+        );
+        for trajectory in trajectories {
+            let mut streamed = Vec::new();
+            let mut emitted = 0;
+            for (i, p) in trajectory.iter().enumerate() {
+                for piece in encoder.push(p.clone()) {
+                    emitted += match piece {
+                        SubTrajectory::Reference(_, covered, _) => covered,
+                        SubTrajectory::Trajectory(_) => 1,
+                    };
+                    streamed.push(piece);
+                }
+                assert!(i + 1 - emitted < max_latency);
+            }
+            streamed.extend(encoder.finish());
+            let (decoded, spans) = EncodedTrajectory(streamed).decode();
+            assert_eq!(spans.last().unwrap().source.end, trajectory.len());
+            for span in spans {
+                let error = max_dtw(
+                    &trajectory[span.source.clone()],
+                    &decoded[span.decoded.clone()],
+                    0,
+                    PointMetric::Haversine,
+                );
+                assert!(error < 0.2);
+            }
+        }
+    }
 }
@@ -0,0 +1,294 @@
+use crate::error::Error;
+use crate::rest::Point;
+use crate::source::TrajectorySource;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Side of the grid cells of `Sampling::StratifiedCell` in microdegrees, about a kilometre
+pub const STRATUM_CELL: i32 = 10_000;
+
+/// How the reference sample and the trajectories to compress are drawn from the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Sampling {
+    /// The first rows of the input, then the rows after them
+    #[default]
+    Head,
+    /// Rows drawn uniformly at random, reading the input twice
+    Random,
+    /// Rows drawn uniformly at random in one pass over the input, holding only the rows drawn
+    Reservoir,
+    /// Rows drawn at random in proportion to the grid cells the trips start in
+    StratifiedCell,
+    /// Rows drawn at random in proportion to trip lengths, in classes of points doubling in size
+    StratifiedLength,
+}
+
+/// The reference sample and the trajectories to compress
+pub type Drawn = (Vec<Vec<Point>>, Vec<Vec<Point>>);
+
+impl Sampling {
+    /// Suffix of the mode name in the logs, followed by the seed when the sampling is random
+    pub fn suffix(self, seed: u64) -> String {
+        let name = match self {
+            Sampling::Head => return String::new(),
+            Sampling::Random => "-RND",
+            Sampling::Reservoir => "-RSV",
+            Sampling::StratifiedCell => "-STRC",
+            Sampling::StratifiedLength => "-STRL",
+        };
+        format!("{name}{seed}")
+    }
+
+    /// Draws `sample_size` trajectories to build the reference set from and `n` other
+    /// trajectories to compress. The random draws only depend on `seed`, and come in the
+    /// order they were drawn in rather than in the order of the input.
+    pub fn draw(
+        self,
+        source: &dyn TrajectorySource,
+        seed: u64,
+        sample_size: usize,
+        n: usize,
+    ) -> Result<Drawn, Error> {
+        let mut rng = Rng::new(seed);
+        let rows = match self {
+            Sampling::Head => read_rows(source, sample_size + n, Some)?,
+            Sampling::Random => {
+                let len = count_rows(source)?;
+                let mut indices = (0..len).collect::<Vec<_>>();
+                rng.partial_shuffle(&mut indices, sample_size + n);
+                indices.truncate(sample_size + n);
+                read_drawn(source, &indices)?
+            }
+            Sampling::Reservoir => {
+                let capacity = sample_size + n;
+                let mut reservoir = Vec::with_capacity(capacity);
+                for (i, t) in source.trajectories()?.enumerate() {
+                    let points = t?.points;
+                    if reservoir.len() < capacity {
+                        reservoir.push(points);
+                    } else {
+                        let slot = rng.below(i + 1);
+                        if slot < capacity {
+                            reservoir[slot] = points;
+                        }
+                    }
+                }
+                rng.shuffle(&mut reservoir);
+                reservoir
+            }
+            Sampling::StratifiedCell | Sampling::StratifiedLength => {
+                let mut strata: BTreeMap<(i32, i32), Vec<usize>> = BTreeMap::new();
+                for (i, t) in source.trajectories()?.enumerate() {
+                    strata.entry(self.stratum(&t?.points)).or_default().push(i);
+                }
+                // The sample is stratified on its own, so it keeps the proportions however small it is
+                let mut indices = stratified_draw(&mut strata, sample_size, &mut rng);
+                indices.extend(stratified_draw(&mut strata, n, &mut rng));
+                read_drawn(source, &indices)?
+            }
+        };
+        let mut sample = rows;
+        let rest = sample.split_off(sample_size.min(sample.len()));
+        Ok((sample, rest))
+    }
+
+    /// The stratum of a trip: the cell of its first point, or its class of length
+    fn stratum(self, points: &[Point]) -> (i32, i32) {
+        match (self, points.first()) {
+            (Sampling::StratifiedCell, Some(p)) => (
+                p.lat.div_euclid(STRATUM_CELL),
+                p.lng.div_euclid(STRATUM_CELL),
+            ),
+            (Sampling::StratifiedLength, _) => ((points.len() + 1).ilog2() as i32, 0),
+            _ => (i32::MIN, i32::MIN),
+        }
+    }
+}
+
+/// SplitMix64, a small generator whose output only depends on its seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`
+    pub fn below(&mut self, bound: usize) -> usize {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as usize
+    }
+
+    /// Moves `count` items drawn at random to the front, in the order they were drawn
+    pub fn partial_shuffle<T>(&mut self, items: &mut [T], count: usize) {
+        for i in 0..count.min(items.len()) {
+            let j = i + self.below(items.len() - i);
+            items.swap(i, j);
+        }
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        self.partial_shuffle(items, items.len());
+    }
+}
+
+/// Draws `count` rows out of `strata`, taking from each stratum in proportion to its
+/// size and giving the rows left over by rounding to the strata with the largest
+/// remainders. The rows drawn are removed from `strata` and returned in random order.
+fn stratified_draw(
+    strata: &mut BTreeMap<(i32, i32), Vec<usize>>,
+    count: usize,
+    rng: &mut Rng,
+) -> Vec<usize> {
+    let total = strata.values().map(|rows| rows.len()).sum::<usize>();
+    let count = count.min(total);
+    if count == 0 {
+        return Vec::new();
+    }
+    let mut quotas = strata
+        .iter()
+        .map(|(&key, rows)| (key, rows.len() * count / total, rows.len() * count % total))
+        .collect::<Vec<_>>();
+    let left_over = count - quotas.iter().map(|&(_, quota, _)| quota).sum::<usize>();
+    let mut by_remainder = (0..quotas.len()).collect::<Vec<_>>();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(quotas[i].2));
+    for &i in &by_remainder[..left_over] {
+        quotas[i].1 += 1;
+    }
+
+    let mut drawn = Vec::with_capacity(count);
+    for (key, quota, _) in quotas {
+        let rows = strata
+            .get_mut(&key)
+            .expect("quotas are taken from the strata");
+        rng.partial_shuffle(rows, quota);
+        drawn.extend(rows.drain(..quota));
+    }
+    rng.shuffle(&mut drawn);
+    drawn
+}
+
+fn count_rows(source: &dyn TrajectorySource) -> Result<usize, Error> {
+    source
+        .trajectories()?
+        .try_fold(0, |len, t| t.map(|_| len + 1))
+}
+
+/// Reads the rows at `indices`, in the order of `indices`
+fn read_drawn(source: &dyn TrajectorySource, indices: &[usize]) -> Result<Vec<Vec<Point>>, Error> {
+    let slots = indices
+        .iter()
+        .enumerate()
+        .map(|(slot, &i)| (i, slot))
+        .collect::<HashMap<_, _>>();
+    read_rows(source, indices.len(), |i| slots.get(&i).copied())
+}
+
+/// Reads the `len` rows `slot` gives a place to, in the order of their places, and
+/// stops once they are read
+fn read_rows(
+    source: &dyn TrajectorySource,
+    len: usize,
+    slot: impl Fn(usize) -> Option<usize>,
+) -> Result<Vec<Vec<Point>>, Error> {
+    let mut rows = vec![None; len];
+    let mut read = 0;
+    for (i, t) in source.trajectories()?.enumerate() {
+        if read == len {
+            break;
+        }
+        if let Some(place) = slot(i) {
+            rows[place] = Some(t?.points);
+            read += 1;
+        }
+    }
+    Ok(rows.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::tests::sample_points;
+    use crate::source::CsvSource;
+
+    const RANDOM_MODES: [Sampling; 4] = [
+        Sampling::Random,
+        Sampling::Reservoir,
+        Sampling::StratifiedCell,
+        Sampling::StratifiedLength,
+    ];
+
+    /// sample.csv, and the rows of it in order
+    fn sample() -> (CsvSource, Vec<Vec<Point>>) {
+        let source = CsvSource::new("sample.csv");
+        (source, sample_points())
+    }
+
+    #[test]
+    fn test_head_takes_the_first_rows() {
+        let (source, rows) = sample();
+        let (sample_size, n) = (rows.len() / 4, rows.len() / 2);
+        let (sample, rest) = Sampling::Head.draw(&source, 7, sample_size, n).unwrap();
+        assert_eq!(sample, rows[..sample_size]);
+        assert_eq!(rest, rows[sample_size..sample_size + n]);
+        assert_eq!(Sampling::Head.suffix(7), "");
+    }
+
+    #[test]
+    fn test_random_modes_depend_on_the_seed_only() {
+        let (source, rows) = sample();
+        let (sample_size, n) = (rows.len() / 4, rows.len() / 2);
+        for sampling in RANDOM_MODES {
+            let drawn = sampling.draw(&source, 7, sample_size, n).unwrap();
+            assert_eq!(sampling.draw(&source, 7, sample_size, n).unwrap(), drawn);
+            assert_ne!(
+                sampling.draw(&source, 8, sample_size, n).unwrap().0,
+                drawn.0
+            );
+            assert_ne!(drawn.0, rows[..sample_size]);
+            assert!(sampling.suffix(7).ends_with('7'));
+        }
+    }
+
+    #[test]
+    fn test_random_modes_draw_every_row_at_most_once() {
+        let (source, rows) = sample();
+        let (sample_size, n) = (rows.len() / 4, rows.len() / 2);
+        for sampling in RANDOM_MODES {
+            let (sample, rest) = sampling.draw(&source, 7, sample_size, n).unwrap();
+            assert_eq!((sample.len(), rest.len()), (sample_size, n));
+            let drawn = sample.iter().chain(&rest).collect::<Vec<_>>();
+            let distinct = rows.iter().filter(|row| drawn.contains(row)).count();
+            assert_eq!(distinct, drawn.len());
+        }
+    }
+
+    #[test]
+    fn test_stratified_sample_keeps_the_strata_in_proportion() {
+        let (source, rows) = sample();
+        let sample_size = rows.len() / 4;
+        let sampling = Sampling::StratifiedLength;
+        let (sample, _) = sampling.draw(&source, 7, sample_size, 0).unwrap();
+        let mut strata = BTreeMap::new();
+        for row in &rows {
+            strata.entry(sampling.stratum(row)).or_insert((0, 0)).0 += 1;
+        }
+        for row in &sample {
+            strata.get_mut(&sampling.stratum(row)).unwrap().1 += 1;
+        }
+        // Within one row each
+        for (in_input, in_sample) in strata.into_values() {
+            let expected = in_input as f64 * sample_size as f64 / rows.len() as f64;
+            assert!((in_sample as f64 - expected).abs() < 1.0);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::Error;
+use crate::rest::Point;
+use crate::temporal::sample_times;
+use clap::ValueEnum;
+use std::path::PathBuf;
+
+/// A trajectory as read from a dataset, before any compression
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceTrajectory {
+    pub id: Option<String>,
+    /// Start time in seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub points: Vec<Point>,
+}
+
+pub type TrajectoryIter<'a> = Box<dyn Iterator<Item = Result<SourceTrajectory, Error>> + 'a>;
+
+/// Streams the trajectories of a dataset in file order. Every call starts from the beginning.
+pub trait TrajectorySource {
+    fn trajectories(&self) -> Result<TrajectoryIter<'_>, Error>;
+}
+
+/// Axis order of the positions in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum CoordinateOrder {
+    LatLng,
+    /// The order of the Porto taxi dataset
+    #[default]
+    LngLat,
+    /// `[lng, lat]` with an optional altitude that is ignored
+    #[value(name = "geojson")]
+    GeoJson,
+}
+
+impl CoordinateOrder {
+    /// Reads a position in this order, rejecting positions of the wrong arity and
+    /// coordinates outside the valid latitude and longitude ranges
+    pub fn point(self, position: &[f32]) -> Result<Point, Error> {
+        let (lat, lng) = match (self, position) {
+            (CoordinateOrder::LatLng, &[lat, lng]) => (lat, lng),
+            (CoordinateOrder::LngLat, &[lng, lat]) => (lat, lng),
+            (CoordinateOrder::GeoJson, &[lng, lat] | &[lng, lat, _]) => (lat, lng),
+            _ => {
+                return Err(Error::Input(format!(
+                    "position {position:?} does not match the {self:?} axis order"
+                )))
+            }
+        };
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+            return Err(Error::Input(format!(
+                "position {position:?} is out of range as {self:?}"
+            )));
+        }
+        Ok(Point::from((lat, lng)))
+    }
+
+    /// Writes a point back as a position in this order
+    pub fn position(self, point: &Point) -> [f32; 2] {
+        match self {
+            CoordinateOrder::LatLng => [point.lat_as_f32(), point.lng_as_f32()],
+            CoordinateOrder::LngLat | CoordinateOrder::GeoJson => {
+                [point.lng_as_f32(), point.lat_as_f32()]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMapping {
+    pub id: Option<String>,
+    /// Column holding the trajectory as a JSON array of coordinate pairs
+    pub polyline: String,
+    pub timestamp: Option<String>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            id: None,
+            polyline: String::from("polyline"),
+            timestamp: None,
+        }
+    }
+}
+
+/// A CSV file with one trajectory per row, such as the Porto taxi dataset
+#[derive(Debug, Clone)]
+pub struct CsvSource {
+    pub path: PathBuf,
+    pub columns: ColumnMapping,
+    pub coordinate_order: CoordinateOrder,
+    /// Seconds between two points. Together with the timestamp column it gives every
+    /// point a time, 15 for the Porto dataset.
+    pub sampling_interval: Option<i64>,
+}
+
+impl CsvSource {
+    pub fn new(path: impl Into<PathBuf>) -> CsvSource {
+        CsvSource {
+            path: path.into(),
+            columns: ColumnMapping::default(),
+            coordinate_order: CoordinateOrder::default(),
+            sampling_interval: None,
+        }
+    }
+}
+
+fn column_index(headers: &csv::StringRecord, name: &str) -> Result<usize, Error> {
+    headers
+        .iter()
+        .position(|header| header == name)
+        .ok_or_else(|| Error::Input(format!("column `{name}` not found in the header")))
+}
+
+impl TrajectorySource for CsvSource {
+    fn trajectories(&self) -> Result<TrajectoryIter<'_>, Error> {
+        let mut reader = csv::Reader::from_path(&self.path)?;
+        let headers = reader.headers()?;
+        let polyline = column_index(headers, &self.columns.polyline)?;
+        let id = self
+            .columns
+            .id
+            .as_deref()
+            .map(|name| column_index(headers, name))
+            .transpose()?;
+        let timestamp = self
+            .columns
+            .timestamp
+            .as_deref()
+            .map(|name| column_index(headers, name))
+            .transpose()?;
+        let order = self.coordinate_order;
+        let interval = self.sampling_interval;
+
+        Ok(Box::new(reader.into_records().map(move |record| {
+            let record = record?;
+            let field = |i: usize| record.get(i).unwrap_or_default();
+            let positions: Vec<Vec<f32>> = serde_json::from_str(field(polyline))?;
+            let mut trajectory = SourceTrajectory {
+                id: id.map(|i| field(i).to_string()),
+                timestamp: timestamp
+                    .map(|i| {
+                        field(i)
+                            .parse()
+                            .map_err(|_| Error::Input(format!("invalid timestamp `{}`", field(i))))
+                    })
+                    .transpose()?,
+                points: positions
+                    .iter()
+                    .map(|position| order.point(position))
+                    .collect::<Result<_, _>>()?,
+            };
+            if let (Some(start), Some(interval)) = (trajectory.timestamp, interval) {
+                sample_times(&mut trajectory.points, start, interval);
+            }
+            Ok(trajectory)
+        })))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The points of every trajectory of sample.csv, in file order
+    pub fn sample_points() -> Vec<Vec<Point>> {
+        CsvSource::new("sample.csv")
+            .trajectories()
+            .unwrap()
+            .map(|t| t.unwrap().points)
+            .collect()
+    }
+
+    /// The longest trajectory of sample.csv, one point every 15 seconds
+    pub fn longest_timed_trip() -> Vec<Point> {
+        let mut points = sample_points().into_iter().max_by_key(|t| t.len()).unwrap();
+        sample_times(&mut points, 0, 15);
+        points
+    }
+
+    #[test]
+    fn test_csv_source_column_mapping_and_order() {
+        let mut source = CsvSource::new("sample.csv");
+        source.columns.id = Some(String::from("id"));
+        source.coordinate_order = CoordinateOrder::LatLng;
+        let trajectories = source
+            .trajectories()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(trajectories.len(), 29);
+        assert_eq!(trajectories[1].id.as_deref(), Some("2"));
+        assert_eq!(trajectories[0].timestamp, None);
+
+        source.coordinate_order = CoordinateOrder::LngLat;
+        let first = source.trajectories().unwrap().next().unwrap().unwrap();
+        assert_eq!(first.points[0].lat, trajectories[0].points[0].lng);
+        assert_eq!(first.points[0].lng, trajectories[0].points[0].lat);
+
+        assert!((41_000_000..42_000_000).contains(&first.points[0].lat));
+
+        // Without a timestamp column the sampling interval times nothing
+        source.sampling_interval = Some(15);
+        assert_eq!(
+            source.trajectories().unwrap().next().unwrap().unwrap(),
+            first
+        );
+        source.columns.timestamp = Some(String::from("id"));
+        let timed = source.trajectories().unwrap().nth(1).unwrap().unwrap();
+        assert_eq!(timed.timestamp, Some(2));
+        assert_eq!(timed.points[3].time, Some(2 + 3 * 15));
+
+        source.columns.timestamp = Some(String::from("TIMESTAMP"));
+        assert!(source.trajectories().is_err());
+
+        assert!(CoordinateOrder::GeoJson.point(&[-8.6, 41.1, 12.0]).is_ok());
+        assert!(CoordinateOrder::LngLat.point(&[-8.6, 41.1, 12.0]).is_err());
+        assert!(CoordinateOrder::LatLng.point(&[141.1, -8.6]).is_err());
+    }
+}
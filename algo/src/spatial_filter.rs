@@ -1,3 +1,4 @@
+use crate::metric::PointMetric;
 use crate::rest::Point;
 use rstar::{PointDistance, RTree, RTreeObject, AABB};
 
@@ -5,6 +6,7 @@ use rstar::{PointDistance, RTree, RTreeObject, AABB};
 pub struct PointWithIndexReference {
     pub point: Point,
     pub index: (usize, usize),
+    pub metric: PointMetric,
 }
 
 impl RTreeObject for PointWithIndexReference {
@@ -16,14 +18,38 @@ impl RTreeObject for PointWithIndexReference {
 }
 impl PointDistance for PointWithIndexReference {
     fn distance_2(&self, point: &[f32; 2]) -> f32 {
-        self.point.distance(&Point::from((point[0], point[1]))) as f32
+        self.metric
+            .distance(&self.point, &Point::from((point[0], point[1]))) as f32
     }
 }
+/// Bulk loads a tree over every point of the reference set, indexed by (trajectory, point)
+pub fn bulk_load_reference_set(
+    reference_set: &[Vec<Point>],
+    metric: PointMetric,
+) -> RTree<PointWithIndexReference> {
+    RTree::bulk_load(
+        reference_set
+            .iter()
+            .enumerate()
+            .flat_map(|(i, t)| {
+                t.iter()
+                    .enumerate()
+                    .map(move |(j, point)| PointWithIndexReference {
+                        point: point.clone(),
+                        index: (i, j),
+                        metric,
+                    })
+            })
+            .collect(),
+    )
+}
 pub trait SpatialQuery {
+    /// The points in a box around `center_point` reaching `envelope_size` meters across
     fn points_within_envelope(
         &self,
         envelope_size: f64,
         center_point: Point,
+        metric: PointMetric,
     ) -> Vec<&PointWithIndexReference>;
 }
 impl SpatialQuery for RTree<PointWithIndexReference> {
@@ -31,19 +57,19 @@ impl SpatialQuery for RTree<PointWithIndexReference> {
         &self,
         envelope_size: f64,
         center_point: Point,
+        metric: PointMetric,
     ) -> Vec<&PointWithIndexReference> {
-        let dia_from_center = envelope_size / 2.0;
-        let lat_d = (dia_from_center / 111319.9) as f32;
-        let lng_d =
-            dia_from_center as f32 / (111319.9 * center_point.lat_as_f32().to_radians().cos());
+        let (lat_d, lng_d) = metric.half_extent(&center_point, envelope_size / 2.0 / 1000.0);
+        // The tree holds coordinates in millionths of the units of `Point`
+        let (lat_d, lng_d) = ((lat_d / 1000000.0) as f32, (lng_d / 1000000.0) as f32);
         self.locate_in_envelope(&AABB::from_corners(
             [
-                center_point.lat_as_f32() + lat_d as f32,
-                center_point.lng_as_f32() + lng_d as f32,
+                center_point.lat_as_f32() + lat_d,
+                center_point.lng_as_f32() + lng_d,
             ],
             [
-                center_point.lat_as_f32() - lat_d as f32,
-                center_point.lng_as_f32() - lng_d as f32,
+                center_point.lat_as_f32() - lat_d,
+                center_point.lng_as_f32() - lng_d,
             ],
         ))
         .collect::<Vec<_>>()
@@ -0,0 +1,356 @@
+use crate::algorithm::{Config, DpMode, Mode, RestMode};
+use crate::builder::{BuilderKind, SimilarityKind};
+use crate::distance::DistanceKind;
+use crate::dp::Simplifier;
+use crate::error::Error;
+use crate::metric::PointMetric;
+use crate::projection::ProjectionKind;
+use crate::reference_set::EvictionPolicy;
+use crate::sampling::Sampling;
+use itertools::iproduct;
+use serde::Deserialize;
+use std::path::Path;
+
+/// A parameter given either as a single value or as a list of values to sweep over
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: Clone> OneOrMany<T> {
+    pub fn values(&self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value.clone()],
+            OneOrMany::Many(values) => values.clone(),
+        }
+    }
+}
+
+/// A grid of experiments. Has the same layout as a single `Config`, but every
+/// parameter may be a list, and both `[mode.rest]` and `[mode.dp]` may be given.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Sweep {
+    pub n: OneOrMany<i32>,
+    #[serde(alias = "error_trajectory")]
+    pub max_dtw_dist: OneOrMany<i32>,
+    #[serde(default = "unbanded")]
+    pub dtw_band: OneOrMany<usize>,
+    #[serde(default = "max_dtw")]
+    pub distance: OneOrMany<DistanceKind>,
+    #[serde(default = "haversine")]
+    pub metric: OneOrMany<PointMetric>,
+    #[serde(default = "unprojected")]
+    pub projection: OneOrMany<ProjectionKind>,
+    #[serde(default = "head")]
+    pub sampling: OneOrMany<Sampling>,
+    /// Seeds of the random sampling modes, each drawing its own sample. The head of the
+    /// input takes the first one only.
+    #[serde(default = "seed_zero")]
+    pub seed: OneOrMany<u64>,
+    pub mode: SweepModes,
+}
+
+fn unbanded() -> OneOrMany<usize> {
+    OneOrMany::One(0)
+}
+
+fn max_dtw() -> OneOrMany<DistanceKind> {
+    OneOrMany::One(DistanceKind::MaxDtw)
+}
+
+fn haversine() -> OneOrMany<PointMetric> {
+    OneOrMany::One(PointMetric::Haversine)
+}
+
+fn unprojected() -> OneOrMany<ProjectionKind> {
+    OneOrMany::One(ProjectionKind::None)
+}
+
+fn head() -> OneOrMany<Sampling> {
+    OneOrMany::One(Sampling::Head)
+}
+
+fn seed_zero() -> OneOrMany<u64> {
+    OneOrMany::One(0)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SweepModes {
+    pub rest: Option<RestSweep>,
+    pub dp: Option<DpSweep>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DpSweep {
+    #[serde(default = "douglas_peucker")]
+    pub simplifier: OneOrMany<Simplifier>,
+}
+
+fn douglas_peucker() -> OneOrMany<Simplifier> {
+    OneOrMany::One(Simplifier::Dp)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RestSweep {
+    pub rs: OneOrMany<i32>,
+    pub compression_ratio: OneOrMany<i32>,
+    pub spatial_filter: OneOrMany<bool>,
+    pub include_entire_trajectory: OneOrMany<bool>,
+    pub k: OneOrMany<usize>,
+    pub error_point: OneOrMany<i32>,
+    /// Budgets in points, the set is unbounded when omitted
+    pub budget: Option<OneOrMany<usize>>,
+    #[serde(default = "least_recently_used")]
+    pub eviction: OneOrMany<EvictionPolicy>,
+    #[serde(default = "greedy")]
+    pub builder: OneOrMany<BuilderKind>,
+    /// Set sizes of the clustering builders, caps of the segment builder
+    pub set_size: Option<OneOrMany<usize>>,
+    #[serde(default = "run_distance")]
+    pub similarity: OneOrMany<SimilarityKind>,
+}
+
+fn greedy() -> OneOrMany<BuilderKind> {
+    OneOrMany::One(BuilderKind::Greedy)
+}
+
+fn run_distance() -> OneOrMany<SimilarityKind> {
+    OneOrMany::One(SimilarityKind::Distance)
+}
+
+fn least_recently_used() -> OneOrMany<EvictionPolicy> {
+    OneOrMany::One(EvictionPolicy::LeastRecentlyUsed)
+}
+
+impl Sweep {
+    /// Expands the sweep into one config per combination of parameter values
+    pub fn configs(&self) -> Vec<Config> {
+        let mut modes = Vec::new();
+        if let Some(rest) = &self.mode.rest {
+            modes.extend(
+                iproduct!(
+                    rest.rs.values(),
+                    rest.compression_ratio.values(),
+                    rest.spatial_filter.values(),
+                    rest.include_entire_trajectory.values(),
+                    rest.k.values(),
+                    rest.error_point.values(),
+                    match &rest.budget {
+                        Some(budget) => budget.values().into_iter().map(Some).collect(),
+                        None => vec![None],
+                    },
+                    rest.eviction.values(),
+                    rest.builder.values(),
+                    match &rest.set_size {
+                        Some(set_size) => set_size.values().into_iter().map(Some).collect(),
+                        None => vec![None],
+                    },
+                    rest.similarity.values()
+                )
+                .map(
+                    |(
+                        rs,
+                        compression_ratio,
+                        spatial_filter,
+                        include_entire_trajectory,
+                        k,
+                        error_point,
+                        budget,
+                        eviction,
+                        builder,
+                        set_size,
+                        similarity,
+                    )| {
+                        Mode::Rest(RestMode {
+                            rs,
+                            compression_ratio,
+                            spatial_filter,
+                            include_entire_trajectory,
+                            k,
+                            error_point,
+                            budget,
+                            eviction,
+                            builder,
+                            set_size,
+                            similarity,
+                        })
+                    },
+                ),
+            );
+        }
+        if let Some(dp) = &self.mode.dp {
+            modes.extend(
+                dp.simplifier
+                    .values()
+                    .into_iter()
+                    .map(|simplifier| Mode::DP(DpMode { simplifier })),
+            );
+        }
+        // The head of the input takes no seed, so it runs once rather than once per seed
+        let seeds = self.seed.values();
+        let drawn = self
+            .sampling
+            .values()
+            .into_iter()
+            .flat_map(|sampling| {
+                let seeds = match sampling {
+                    Sampling::Head => &seeds[..seeds.len().min(1)],
+                    _ => &seeds[..],
+                };
+                seeds.iter().map(move |&seed| (sampling, seed))
+            })
+            .collect::<Vec<_>>();
+        iproduct!(
+            self.n.values(),
+            self.max_dtw_dist.values(),
+            self.dtw_band.values(),
+            self.distance.values(),
+            self.metric.values(),
+            self.projection.values(),
+            drawn,
+            modes
+        )
+        .map(
+            |(n, max_dtw_dist, dtw_band, distance, metric, projection, (sampling, seed), mode)| {
+                Config {
+                    n,
+                    max_dtw_dist,
+                    dtw_band,
+                    distance,
+                    metric,
+                    projection,
+                    sampling,
+                    seed,
+                    mode,
+                }
+            },
+        )
+        .collect()
+    }
+}
+
+/// Parses a sweep and validates every config it expands to, so that nothing runs unless all of it can
+pub fn parse_sweep(text: &str) -> Result<Vec<Config>, Error> {
+    let sweep: Sweep = toml::from_str(text).map_err(|e| Error::Config(e.to_string()))?;
+    let configs = sweep.configs();
+    if configs.is_empty() {
+        return Err(Error::Config(String::from("the sweep expands to no runs")));
+    }
+    let problems = configs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, conf)| {
+            conf.validate()
+                .into_iter()
+                .map(move |problem| format!("run {}: {}", i + 1, problem))
+        })
+        .collect::<Vec<_>>();
+    if !problems.is_empty() {
+        return Err(Error::Config(problems.join("\n")));
+    }
+    Ok(configs)
+}
+
+pub fn load_sweep(path: impl AsRef<Path>) -> Result<Vec<Config>, Error> {
+    parse_sweep(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::mode_name;
+
+    #[test]
+    fn test_sweep_expands_grid_and_validates() {
+        let configs = parse_sweep(
+            r#"
+            n = 1000
+            max_dtw_dist = [100, 200]
+            distance = "frechet"
+            metric = { planar = { latitude = 41.15 } }
+
+            [mode.rest]
+            rs = [100, 500, 1000]
+            compression_ratio = 5
+            spatial_filter = true
+            include_entire_trajectory = true
+            k = 3
+            error_point = 5
+
+            [mode.dp]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 2 * (3 + 1));
+        assert!(matches!(
+            configs[2].mode,
+            Mode::Rest(RestMode { rs: 1000, .. })
+        ));
+        assert_eq!(configs[4].max_dtw_dist, 200);
+        assert_eq!(configs[4].distance, DistanceKind::Frechet);
+        assert_eq!(configs[4].metric, PointMetric::Planar { latitude: 41.15 });
+
+        let single: Config = toml::from_str("n = 10\nerror_trajectory = 200\n[mode.dp]\n").unwrap();
+        assert_eq!(single.max_dtw_dist, 200);
+        assert_eq!(single.distance, DistanceKind::MaxDtw);
+        assert_eq!(single.metric, PointMetric::Haversine);
+        assert_eq!(single.projection, ProjectionKind::None);
+
+        let projected =
+            parse_sweep("n = 10\nmax_dtw_dist = 200\nprojection = \"utm\"\n[mode.dp]\n").unwrap();
+        assert_eq!(projected[0].point_metric(), PointMetric::Projected);
+        let both = parse_sweep(
+            "n = 10\nmax_dtw_dist = 200\nmetric = \"equirectangular\"\nprojection = \"local\"\n[mode.dp]\n",
+        );
+        assert!(both.unwrap_err().to_string().contains("unprojected points"));
+
+        let unknown = parse_sweep("n = 10\nmax_dtw_dist = 200\nbogus = 1\n[mode.dp]\n");
+        assert!(unknown.unwrap_err().to_string().contains("bogus"));
+
+        let simplifiers = parse_sweep(
+            "n = 10\nmax_dtw_dist = 200\n[mode.dp]\nsimplifier = [\"dp\", \"td_tr\", \"visvalingam\"]\n",
+        )
+        .unwrap();
+        assert_eq!(simplifiers.len(), 3);
+        assert!(matches!(
+            simplifiers[1].mode,
+            Mode::DP(DpMode {
+                simplifier: Simplifier::TdTr
+            })
+        ));
+        assert_eq!(mode_name(&simplifiers[1]), "TDTR");
+
+        let out_of_range = parse_sweep("n = [10, -1]\nmax_dtw_dist = 200\n[mode.dp]\n");
+        assert!(out_of_range
+            .unwrap_err()
+            .to_string()
+            .contains("run 2: n must be positive"));
+    }
+
+    #[test]
+    fn test_sweep_runs_the_head_once_whatever_the_seeds() {
+        let configs = parse_sweep(
+            "n = 10\nmax_dtw_dist = 200\nsampling = [\"head\", \"random\"]\nseed = [1, 2, 3]\n[mode.dp]\n",
+        )
+        .unwrap();
+        let drawn = configs
+            .iter()
+            .map(|conf| (conf.sampling, conf.seed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            drawn,
+            vec![
+                (Sampling::Head, 1),
+                (Sampling::Random, 1),
+                (Sampling::Random, 2),
+                (Sampling::Random, 3)
+            ]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use crate::distance::{DistanceState, TrajectoryDistance};
+use crate::max_dtw::Envelope;
+use crate::metric::PointMetric;
+use crate::rest::Point;
+
+/// Whether every point has a time, the only case in which times are stored
+pub fn is_timed(points: &[Point]) -> bool {
+    points.iter().all(|p| p.time.is_some())
+}
+
+/// Gives the points of a trajectory starting at `start` one time every `interval` seconds,
+/// the way the Porto dataset is sampled
+pub fn sample_times(points: &mut [Point], start: i64, interval: i64) {
+    for (i, p) in points.iter_mut().enumerate() {
+        p.time = Some(start + i as i64 * interval);
+    }
+}
+
+/// Seconds since the first point, or the index of each point when some point has no time
+pub fn relative_times(points: &[Point]) -> Vec<f64> {
+    match points.first().and_then(|p| p.time) {
+        Some(first) if is_timed(points) => points
+            .iter()
+            .map(|p| (p.time.unwrap_or(first) - first) as f64)
+            .collect(),
+        _ => (0..points.len()).map(|i| i as f64).collect(),
+    }
+}
+
+/// How the times of a reference slice are mapped onto the source piece it stands in for:
+/// the slice starts at `offset` and is stretched to last `duration` seconds, so a decoded
+/// trajectory keeps the start, end and duration of every piece. A slice that takes no time
+/// is not stretched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeMapping {
+    pub offset: i64,
+    pub duration: i64,
+}
+
+impl TimeMapping {
+    /// The mapping of a reference slice onto `source`, None when the source has no times
+    pub fn fit(source: &[Point]) -> Option<TimeMapping> {
+        let (first, last) = (source.first()?.time?, source.last()?.time?);
+        is_timed(source).then_some(TimeMapping {
+            offset: first,
+            duration: last - first,
+        })
+    }
+
+    /// Factor from the relative times of `reference` to the decoded ones
+    fn scale(&self, reference: &[f64]) -> f64 {
+        match reference.last() {
+            Some(&span) if span > 0.0 => self.duration as f64 / span,
+            _ => 1.0,
+        }
+    }
+
+    /// The points of a reference slice with the times they decode to
+    pub fn apply(&self, reference: &[Point]) -> Vec<Point> {
+        let times = relative_times(reference);
+        let scale = self.scale(&times);
+        reference
+            .iter()
+            .zip(times)
+            .map(|(p, t)| Point {
+                time: Some(self.offset + (t * scale).round() as i64),
+                ..p.clone()
+            })
+            .collect()
+    }
+}
+
+/// The distance from every point of `ta` to where `tb` is at the same time, interpolating
+/// linearly between the points of `tb` and holding its end points outside its time span
+fn synchronized_distance(
+    ta: &[Point],
+    times_a: &[f64],
+    tb: &[Point],
+    times_b: &[f64],
+    metric: PointMetric,
+) -> f64 {
+    let mut j = 0;
+    let mut max: f64 = 0.0;
+    for (p, &t) in ta.iter().zip(times_a) {
+        while j + 1 < tb.len() && times_b[j + 1] < t {
+            j += 1;
+        }
+        let synchronized = match tb.get(j + 1) {
+            Some(next) if t > times_b[j] => {
+                let f = (t - times_b[j]) / (times_b[j + 1] - times_b[j]);
+                Point {
+                    lat: tb[j].lat + (f * (next.lat - tb[j].lat) as f64).round() as i32,
+                    lng: tb[j].lng + (f * (next.lng - tb[j].lng) as f64).round() as i32,
+                    time: None,
+                }
+            }
+            _ => tb[j].clone(),
+        };
+        max = max.max(metric.distance(p, &synchronized));
+    }
+    max
+}
+
+/// Synchronized Euclidean distance, taken both ways. The times of `tb` are mapped onto the
+/// span of `ta` the way `TimeMapping` maps a reference slice onto its source piece, so the
+/// distance between a source piece and a reference slice is the largest distance between
+/// a point of either and where the other is at the time that point decodes to.
+/// Trajectories without times are taken to be sampled at a constant rate.
+pub fn sed(ta: &[Point], tb: &[Point], metric: PointMetric) -> f64 {
+    match (ta.is_empty(), tb.is_empty()) {
+        (true, true) => return 0.0,
+        (true, false) | (false, true) => return f64::MAX,
+        _ => {}
+    }
+    let times_a = relative_times(ta);
+    let mut times_b = relative_times(tb);
+    let scale = TimeMapping {
+        offset: 0,
+        duration: times_a[times_a.len() - 1] as i64,
+    }
+    .scale(&times_b);
+    times_b.iter_mut().for_each(|t| *t *= scale);
+    synchronized_distance(ta, &times_a, tb, &times_b, metric)
+        .max(synchronized_distance(tb, &times_b, ta, &times_a, metric))
+}
+
+/// Synchronized Euclidean distance, see `sed`. It bounds the error of the positions a
+/// decoded trajectory has at its decoded times.
+#[derive(Debug, Clone, Copy)]
+pub struct Sed {
+    pub metric: PointMetric,
+}
+
+impl TrajectoryDistance for Sed {
+    fn metric(&self) -> PointMetric {
+        self.metric
+    }
+
+    fn distance(&self, ta: &[Point], tb: &[Point]) -> f64 {
+        sed(ta, tb, self.metric)
+    }
+
+    fn state(&self, _ta: &[Point], tb: &[Point]) -> Box<dyn DistanceState> {
+        Box::new(SedState {
+            metric: self.metric,
+            pushed: (0, 0),
+            distance: 0.0,
+            first: None,
+            envelope: Envelope::of(tb),
+            envelope_bound: 0.0,
+        })
+    }
+
+    /// The first and the last points of both sides are synchronized
+    fn lower_bound_reaches(&self, ta: &[Point], tb: &[Point], threshold: f64) -> bool {
+        match (ta.first(), tb.first(), ta.last(), tb.last()) {
+            (Some(first_a), Some(first_b), Some(last_a), Some(last_b)) => {
+                self.metric.distance(first_a, first_b) >= threshold
+                    || self.metric.distance(last_a, last_b) >= threshold
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Pushing a point moves the time mapping of the whole match, so the distance is taken
+/// again on every push: a push costs time linear in the match, and growing a match point
+/// by point quadratic time. The bounds are what keeps this affordable, as they let
+/// matches that cannot meet the deviation be dropped before they grow.
+#[derive(Clone)]
+struct SedState {
+    metric: PointMetric,
+    pushed: (usize, usize),
+    distance: f64,
+    /// The distance between the first points, once both sides have one
+    first: Option<f64>,
+    /// Bounding box of the whole of `tb` the state was made with
+    envelope: Envelope,
+    /// The largest distance from a pushed point of `ta` to `envelope`
+    envelope_bound: f64,
+}
+
+impl SedState {
+    fn update(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let (a, b) = self.pushed;
+        if a > 0 && b > 0 && self.first.is_none() {
+            self.first = Some(self.metric.distance(&ta[0], &tb[0]));
+        }
+        self.distance = sed(&ta[..a], &tb[..b], self.metric);
+        self.distance
+    }
+
+    /// The first points stay synchronized however far the match is extended, and every
+    /// point of `ta` is synchronized with a position between two points of `tb`, which
+    /// lies in its bounding box. Only `tb` is boxed, so the bound holds however `ta`
+    /// goes on, even past the points it was made with.
+    fn bound(&self) -> f64 {
+        self.first
+            .map_or(0.0, |first| first.max(self.envelope_bound))
+    }
+}
+
+impl DistanceState for SedState {
+    fn pushed(&self) -> (usize, usize) {
+        self.pushed
+    }
+
+    fn push_a(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        let p = &ta[self.pushed.0];
+        self.envelope_bound = self
+            .envelope_bound
+            .max(self.envelope.distance_bound(p, self.metric));
+        self.pushed.0 += 1;
+        self.update(ta, tb)
+    }
+
+    fn push_b(&mut self, ta: &[Point], tb: &[Point]) -> f64 {
+        self.pushed.1 += 1;
+        self.update(ta, tb)
+    }
+
+    fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    fn push_a_bound(&self) -> f64 {
+        self.bound()
+    }
+
+    fn extension_bound(&self) -> f64 {
+        self.bound()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DistanceState> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed_line(lat: i32, times: &[i64]) -> Vec<Point> {
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, &t)| Point {
+                lat: lat + i as i32 * 1000,
+                lng: -8_610_000,
+                time: Some(t),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sed_synchronizes_times_and_mappings_keep_durations() {
+        let metric = PointMetric::Haversine;
+        let source = timed_line(41_150_000, &[100, 115, 130, 145]);
+        // The same path at twice the pace, which the mapping stretches onto the source
+        let reference = timed_line(41_150_000, &[0, 30, 60, 90]);
+        assert_eq!(sed(&source, &reference, metric), 0.0);
+        assert_eq!(
+            sed(&source, &source[..1], metric),
+            sed(&source[..1], &source, metric)
+        );
+        assert_eq!(sed(&[], &[], metric), 0.0);
+        assert_eq!(sed(&source, &[], metric), f64::MAX);
+
+        // Lingering at the start puts the reference behind the source in between
+        let lingering = timed_line(41_150_000, &[0, 40, 60, 90]);
+        let lag = sed(&source, &lingering, metric);
+        assert!(lag > 0.0 && lag < source[0].distance(&source[1]));
+        // Without times, both are taken at a constant rate
+        let untimed = source
+            .iter()
+            .map(|p| Point {
+                time: None,
+                ..p.clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sed(&untimed, &reference, metric), 0.0);
+
+        let mapping = TimeMapping::fit(&source).unwrap();
+        assert_eq!(
+            mapping,
+            TimeMapping {
+                offset: 100,
+                duration: 45
+            }
+        );
+        let decoded = mapping.apply(&reference);
+        assert_eq!(
+            decoded.iter().map(|p| p.time.unwrap()).collect::<Vec<_>>(),
+            vec![100, 115, 130, 145]
+        );
+        assert_eq!(TimeMapping::fit(&untimed), None);
+
+        // Incremental states agree with the distance
+        let distance = Sed { metric };
+        let mut state = distance.state(&source, &lingering);
+        for _ in 0..source.len() {
+            state.push_a(&source, &lingering);
+            state.push_b(&source, &lingering);
+        }
+        assert_eq!(state.distance(), lag);
+        assert!(state.extension_bound() <= lag);
+        assert!(!distance.lower_bound_reaches(&source, &lingering, lag));
+    }
+
+    #[test]
+    fn test_sed_state_bounds_every_extension() {
+        let metric = PointMetric::Haversine;
+        let source = timed_line(41_150_000, &[0, 15, 30, 45]);
+        // Starts where the source starts, then heads east away from it
+        let reference = (0..4)
+            .map(|i| Point {
+                lat: 41_150_000,
+                lng: -8_610_000 + i * 2000,
+                time: Some(i as i64 * 15),
+            })
+            .collect::<Vec<_>>();
+        let distance = Sed { metric };
+        let mut state = distance.state(&source, &reference);
+        state.push_a(&source, &reference);
+        state.push_a(&source, &reference);
+        state.push_b(&source, &reference);
+        // The first points coincide, yet the second source point is off the reference
+        let bound = state.extension_bound();
+        assert!(bound > 0.0);
+        assert_eq!(state.push_a_bound(), bound);
+        for a in 2..=source.len() {
+            for b in 1..=reference.len() {
+                assert!(bound <= sed(&source[..a], &reference[..b], metric));
+            }
+        }
+    }
+}
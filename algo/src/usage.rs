@@ -0,0 +1,382 @@
+use crate::algorithm::{POINT_SIZE, REFERENCE_SIZE};
+use crate::metric::PointMetric;
+use crate::rest::{EncodedTrajectory, Point, ReferenceUse, SubTrajectory};
+use crate::spatial_filter::{bulk_load_reference_set, SpatialQuery};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// What one entry of a reference set earned over a run. Bytes are counted the way
+/// `cr_from_shape` counts them: a reference piece saves the points it covers, less the
+/// reference itself, and the entry costs its own points.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceStats {
+    pub id: usize,
+    pub points: usize,
+    /// Number of reference pieces taken from the entry
+    pub hits: u64,
+    pub covered_points: u64,
+    pub bytes_saved: i64,
+    pub bytes_stored: u64,
+}
+
+/// How a usage report is written, see `UsageReport::write_csv` and `UsageReport::write_json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UsageFormat {
+    Csv,
+    /// One JSON object per run, one run per line
+    Json,
+}
+
+impl UsageFormat {
+    /// File the reports of every run are appended to
+    pub fn file_name(self) -> &'static str {
+        match self {
+            UsageFormat::Csv => "reference_usage.csv",
+            UsageFormat::Json => "reference_usage.jsonl",
+        }
+    }
+}
+
+/// Per entry usage of a reference set, built up from the `ReferenceUse`s of every
+/// trajectory encoded against it
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    pub entries: Vec<ReferenceStats>,
+}
+
+impl UsageReport {
+    pub fn new(reference_set: &[impl AsRef<[Point]>]) -> UsageReport {
+        UsageReport {
+            entries: reference_set
+                .iter()
+                .enumerate()
+                .map(|(id, t)| ReferenceStats {
+                    id,
+                    points: t.as_ref().len(),
+                    hits: 0,
+                    covered_points: 0,
+                    bytes_saved: 0,
+                    bytes_stored: t.as_ref().len() as u64 * POINT_SIZE,
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds the reference pieces of one encoded trajectory
+    pub fn record(&mut self, uses: &[ReferenceUse]) {
+        for reference in uses {
+            let entry = &mut self.entries[reference.id];
+            entry.hits += 1;
+            entry.covered_points += reference.covered as u64;
+            entry.bytes_saved +=
+                (reference.covered as u64 * POINT_SIZE) as i64 - REFERENCE_SIZE as i64;
+        }
+    }
+
+    /// The ids of the entries no trajectory referenced
+    pub fn never_used(&self) -> Vec<usize> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.hits == 0)
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    /// Writes one line per entry, starting with the columns that name the run in set_size.txt:
+    /// `mode,max_dtw_dist,sample_size,id,points,hits,covered_points,bytes_saved,bytes_stored`
+    pub fn write_csv(
+        &self,
+        mut writer: impl Write,
+        mode: &str,
+        max_dtw_dist: i32,
+        sample_size: usize,
+    ) -> io::Result<()> {
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{}",
+                mode,
+                max_dtw_dist,
+                sample_size,
+                entry.id,
+                entry.points,
+                entry.hits,
+                entry.covered_points,
+                entry.bytes_saved,
+                entry.bytes_stored
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the report of a run as one line of JSON, with the never used entries
+    pub fn write_json(
+        &self,
+        mut writer: impl Write,
+        mode: &str,
+        max_dtw_dist: i32,
+        sample_size: usize,
+    ) -> io::Result<()> {
+        #[derive(Serialize)]
+        struct Run<'a> {
+            mode: &'a str,
+            max_dtw_dist: i32,
+            sample_size: usize,
+            never_used: Vec<usize>,
+            entries: &'a [ReferenceStats],
+        }
+        serde_json::to_writer(
+            &mut writer,
+            &Run {
+                mode,
+                max_dtw_dist,
+                sample_size,
+                never_used: self.never_used(),
+                entries: &self.entries,
+            },
+        )?;
+        writeln!(writer)
+    }
+}
+
+/// How much of the trajectories of a run the reference set covers, and how much of the
+/// set repeats itself, to compare how sets are built. Coverage counts the edges of the
+/// trajectories that reference pieces stand in for. A reference point is redundant when
+/// another entry passes within the error bound of it, so it could likely have been
+/// matched there instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub references: usize,
+    pub reference_points: usize,
+    pub redundant_points: usize,
+    pub edges: u64,
+    pub covered_edges: u64,
+}
+
+impl CoverageReport {
+    pub fn new(
+        reference_set: &[Vec<Point>],
+        metric: PointMetric,
+        max_dtw_dist: i32,
+    ) -> CoverageReport {
+        let r_tree = bulk_load_reference_set(reference_set, metric);
+        let bound = max_dtw_dist as f64 / 1000.0;
+        let redundant_points = reference_set
+            .par_iter()
+            .enumerate()
+            .map(|(i, t)| {
+                t.iter()
+                    .filter(|p| {
+                        r_tree
+                            .points_within_envelope(max_dtw_dist as f64 * 2.0, (*p).clone(), metric)
+                            .iter()
+                            .any(|other| {
+                                other.index.0 != i && metric.distance(p, &other.point) <= bound
+                            })
+                    })
+                    .count()
+            })
+            .sum();
+        CoverageReport {
+            references: reference_set.len(),
+            reference_points: reference_set.iter().map(|t| t.len()).sum(),
+            redundant_points,
+            edges: 0,
+            covered_edges: 0,
+        }
+    }
+
+    /// Adds one encoded trajectory of `length` points
+    pub fn record(&mut self, encoded: &EncodedTrajectory, length: usize) {
+        self.edges += length.saturating_sub(1) as u64;
+        for st in &encoded.0 {
+            if let SubTrajectory::Reference(_, covered, _) = st {
+                self.covered_edges += *covered as u64;
+            }
+        }
+    }
+
+    /// Share of the edges covered by reference pieces
+    pub fn coverage(&self) -> f64 {
+        self.covered_edges as f64 / self.edges.max(1) as f64
+    }
+
+    /// Share of the reference points that are redundant
+    pub fn redundancy(&self) -> f64 {
+        self.redundant_points as f64 / self.reference_points.max(1) as f64
+    }
+
+    /// Writes one line for the run, starting with the columns that name it in set_size.txt:
+    /// `mode,max_dtw_dist,sample_size,references,reference_points,redundant_points,edges,covered_edges,coverage,redundancy`
+    pub fn write_csv(
+        &self,
+        mut writer: impl Write,
+        mode: &str,
+        max_dtw_dist: i32,
+        sample_size: usize,
+    ) -> io::Result<()> {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{:.4},{:.4}",
+            mode,
+            max_dtw_dist,
+            sample_size,
+            self.references,
+            self.reference_points,
+            self.redundant_points,
+            self.edges,
+            self.covered_edges,
+            self.coverage(),
+            self.redundancy()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::MaxDtw;
+    use crate::rest::encode;
+    use crate::source::tests::sample_points;
+    use itertools::Itertools;
+
+    /// sample.csv split into 10 references and the trajectories compressed against them
+    fn split_sample() -> (Vec<Vec<Point>>, Vec<Vec<Point>>) {
+        let mut references = sample_points();
+        let trajectories = references.split_off(10);
+        (references, trajectories)
+    }
+
+    fn encode_all<'a>(
+        reference_set: &'a [&'a [Point]],
+        trajectories: &[Vec<Point>],
+    ) -> Vec<(EncodedTrajectory<'a>, (u64, u64, u64))> {
+        let distance = MaxDtw {
+            band: 0,
+            metric: PointMetric::Haversine,
+        };
+        trajectories
+            .iter()
+            .map(|t| encode(reference_set, t, 200.0, &distance, 3, None, 70.0))
+            .collect()
+    }
+
+    /// The usage of the references over all the trajectories, and the number of reference pieces
+    fn usage_report(references: &[Vec<Point>], trajectories: &[Vec<Point>]) -> (UsageReport, u64) {
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        let mut report = UsageReport::new(&reference_set);
+        let mut pieces = 0;
+        for (encoded, (_, references, _)) in encode_all(&reference_set, trajectories) {
+            report.record(&encoded.reference_uses(&reference_set));
+            pieces += references;
+        }
+        (report, pieces)
+    }
+
+    #[test]
+    fn test_reference_uses_resolve_the_encoded_slices() {
+        let (references, trajectories) = split_sample();
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        for (encoded, (_, references, _)) in encode_all(&reference_set, &trajectories) {
+            let uses = encoded.reference_uses(&reference_set);
+            assert_eq!(uses.len() as u64, references);
+            let slices = encoded
+                .0
+                .iter()
+                .filter_map(|st| match st {
+                    SubTrajectory::Reference(slice, covered, _) => Some((slice, covered)),
+                    SubTrajectory::Trajectory(_) => None,
+                })
+                .collect_vec();
+            assert_eq!(uses.len(), slices.len());
+            for (used, (slice, &covered)) in uses.iter().zip(slices) {
+                assert_eq!(&reference_set[used.id][used.start..=used.end], *slice);
+                assert_eq!(used.covered, covered);
+            }
+        }
+    }
+
+    #[test]
+    fn test_usage_report_accounts_for_every_reference() {
+        let (references, trajectories) = split_sample();
+        let (report, pieces) = usage_report(&references, &trajectories);
+
+        let entries = &report.entries;
+        assert_eq!(entries.iter().map(|entry| entry.hits).sum::<u64>(), pieces);
+        assert!(pieces > 0);
+        for entry in entries {
+            assert_eq!(
+                entry.bytes_saved,
+                (entry.covered_points * POINT_SIZE) as i64 - (entry.hits * REFERENCE_SIZE) as i64
+            );
+            assert_eq!(
+                entry.bytes_stored,
+                references[entry.id].len() as u64 * POINT_SIZE
+            );
+        }
+        let never_used = report.never_used();
+        assert!(never_used.iter().all(|&id| entries[id].covered_points == 0));
+    }
+
+    #[test]
+    fn test_usage_report_writes_a_line_per_reference() {
+        let (references, trajectories) = split_sample();
+        let (report, _) = usage_report(&references, &trajectories);
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv, "REST", 200, 10).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), references.len());
+        assert!(csv.starts_with("REST,200,10,0,"));
+        let mut json = Vec::new();
+        report.write_json(&mut json, "REST", 200, 10).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["entries"].as_array().unwrap().len(), references.len());
+        assert_eq!(
+            value["never_used"].as_array().unwrap().len(),
+            report.never_used().len()
+        );
+    }
+
+    #[test]
+    fn test_coverage_counts_the_pieces_the_usage_report_counts() {
+        let (references, trajectories) = split_sample();
+        let (report, _) = usage_report(&references, &trajectories);
+        let reference_set = references.iter().map(|t| t.as_slice()).collect_vec();
+        let mut coverage = CoverageReport::new(&references, PointMetric::Haversine, 200);
+        for ((encoded, _), trajectory) in encode_all(&reference_set, &trajectories)
+            .iter()
+            .zip(&trajectories)
+        {
+            coverage.record(encoded, trajectory.len());
+        }
+
+        assert_eq!(
+            coverage.covered_edges,
+            report
+                .entries
+                .iter()
+                .map(|entry| entry.covered_points)
+                .sum::<u64>()
+        );
+        assert_eq!(
+            coverage.edges,
+            trajectories.iter().map(|t| t.len() as u64 - 1).sum::<u64>()
+        );
+        assert!(coverage.coverage() > 0.0 && coverage.coverage() <= 1.0);
+        assert!(coverage.redundancy() < 1.0);
+        let mut csv = Vec::new();
+        coverage.write_csv(&mut csv, "REST", 200, 10).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().split(',').count(), 10);
+    }
+
+    #[test]
+    fn test_an_entry_stored_twice_is_wholly_redundant() {
+        let (references, _) = split_sample();
+        let twice = [references[0].clone(), references[0].clone()];
+        let doubled = CoverageReport::new(&twice, PointMetric::Haversine, 200);
+        assert_eq!(doubled.redundant_points, doubled.reference_points);
+    }
+}